@@ -0,0 +1,233 @@
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use crate::error::{Error, IoContext, Result};
+use crate::files;
+use crate::progress::Progress;
+use crate::render::{self, RenderFailure};
+use crate::shutdown;
+
+// Atlas layout follows the TexturePacker "JSON (Hash)" format, which Phaser,
+// PixiJS, Cocos and most engine importers understand.
+#[derive(Debug, Serialize)]
+struct Atlas {
+    frames: BTreeMap<String, AtlasFrame>,
+    meta: AtlasMeta,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct AtlasFrame {
+    frame: Rect,
+    rotated: bool,
+    trimmed: bool,
+    sprite_source_size: Rect,
+    source_size: Size,
+}
+
+#[derive(Debug, Serialize)]
+struct AtlasMeta {
+    app: &'static str,
+    version: &'static str,
+    image: String,
+    format: &'static str,
+    size: Size,
+    scale: &'static str,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+struct Rect {
+    x: u32,
+    y: u32,
+    w: u32,
+    h: u32,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+struct Size {
+    w: u32,
+    h: u32,
+}
+
+pub async fn pack_emotions(
+    emotions_dir: &Path,
+    output_dir: &Path,
+    columns: usize,
+    jobs: usize,
+) -> Result<()> {
+    let mut token_dirs = vec![];
+    for entry in std::fs::read_dir(emotions_dir).with_path(emotions_dir)? {
+        let path = entry.with_path(emotions_dir)?.path();
+        let index = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.parse::<usize>().ok());
+        if let Some(index) = index.filter(|_| path.is_dir()) {
+            token_dirs.push((index, path));
+        }
+    }
+    token_dirs.sort_by_key(|(index, _)| *index);
+
+    info!("Number of metronions = {:?}", token_dirs.len());
+
+    std::fs::create_dir_all(output_dir).with_path(output_dir)?;
+
+    let progress = Progress::new("pack", token_dirs.len());
+    let tasks = token_dirs.into_iter().map(|(index, token_dir)| {
+        let parts = vec![token_dir.to_string_lossy().to_string()];
+        let task = tokio::task::spawn_blocking({
+            let output_dir = output_dir.to_path_buf();
+            let parts = parts.clone();
+            move || match pack_token(index, &token_dir, &output_dir, columns) {
+                Ok(()) => vec![],
+                Err(err) => vec![RenderFailure {
+                    token_id: index + 1,
+                    parts,
+                    attempts: 1,
+                    stderr: err.to_string(),
+                }],
+            }
+        });
+        render::join_render(task, index, parts)
+    });
+
+    let report = render::collect(tasks, jobs, &progress).await;
+    if shutdown::is_cancelled() {
+        return Err(Error::Cancelled { checkpoint: None });
+    }
+    render::write_failures(output_dir, &report.failures)
+}
+
+// Emotion frames of a token, ordered by their emotion number.
+fn emotion_frames(index: usize, token_dir: &Path) -> Result<Vec<PathBuf>> {
+    let prefix = format!("{index:}_NFT_Emo_");
+
+    let mut frames = vec![];
+    for entry in std::fs::read_dir(token_dir).with_path(token_dir)? {
+        let path = entry.with_path(token_dir)?.path();
+        let emo = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| stem.strip_prefix(&prefix))
+            .and_then(|emo| emo.parse::<usize>().ok());
+        if let Some(emo) = emo {
+            frames.push((emo, path));
+        }
+    }
+    frames.sort_by_key(|(emo, _)| *emo);
+
+    Ok(frames.into_iter().map(|(_, path)| path).collect())
+}
+
+fn frame_size(index: usize, frame: &Path) -> Result<Size> {
+    let output = std::process::Command::new("magick")
+        .args(["identify", "-format", "%w %h"])
+        .arg(frame)
+        .output()
+        .with_path(frame)?;
+
+    let dimensions = String::from_utf8_lossy(&output.stdout);
+    let dimensions = dimensions
+        .split_whitespace()
+        .map(|item| item.parse::<u32>().ok())
+        .collect::<Option<Vec<u32>>>();
+
+    match dimensions.as_deref() {
+        Some([w, h]) => Ok(Size { w: *w, h: *h }),
+        _ => Err(Error::Render {
+            token_id: index + 1,
+            message: format!(
+                "cannot read the size of {frame:?}: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+        }),
+    }
+}
+
+fn pack_token(index: usize, token_dir: &Path, output_dir: &Path, columns: usize) -> Result<()> {
+    let frames = emotion_frames(index, token_dir)?;
+    if frames.is_empty() {
+        return Err(Error::Render {
+            token_id: index + 1,
+            message: format!("no emotion frames found in {token_dir:?}"),
+        });
+    }
+
+    // every emotion is rendered on the same full canvas, so one size fits all
+    let size = frame_size(index, &frames[0])?;
+    let columns = columns.min(frames.len());
+    let rows = frames.len().div_ceil(columns);
+
+    let sheet_name = format!("{index:}.png");
+    let sheet_file = output_dir.join(&sheet_name);
+    let partial_file = files::partial_path(&sheet_file);
+
+    let output = std::process::Command::new("magick")
+        .args(["montage"])
+        .args(&frames)
+        .args(["-tile", &format!("{columns}x"), "-geometry", "+0+0"])
+        .args(["-background", "none"])
+        .arg(&partial_file)
+        .output()
+        .with_path(&sheet_file)?;
+
+    if !output.status.success() {
+        let _ = std::fs::remove_file(&partial_file);
+        return Err(Error::Render {
+            token_id: index + 1,
+            message: format!(
+                "Command failed with {}: {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+        });
+    }
+
+    let frames = frames
+        .iter()
+        .enumerate()
+        .map(|(i, frame)| {
+            let name = frame
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_default();
+            let rect = Rect {
+                x: (i % columns) as u32 * size.w,
+                y: (i / columns) as u32 * size.h,
+                w: size.w,
+                h: size.h,
+            };
+            let frame = AtlasFrame {
+                frame: rect,
+                rotated: false,
+                trimmed: false,
+                sprite_source_size: Rect { x: 0, y: 0, ..rect },
+                source_size: size,
+            };
+            (name, frame)
+        })
+        .collect::<BTreeMap<String, AtlasFrame>>();
+
+    let atlas = Atlas {
+        frames,
+        meta: AtlasMeta {
+            app: env!("CARGO_PKG_NAME"),
+            version: "1.0",
+            image: sheet_name,
+            format: "RGBA8888",
+            size: Size {
+                w: columns as u32 * size.w,
+                h: rows as u32 * size.h,
+            },
+            scale: "1",
+        },
+    };
+
+    std::fs::rename(&partial_file, &sheet_file).with_path(&sheet_file)?;
+
+    let atlas_file = output_dir.join(format!("{index:}.json"));
+    files::write_json_atomic(&atlas_file, &atlas)?;
+
+    Ok(())
+}
@@ -0,0 +1,224 @@
+use rand::prelude::*;
+use rand::rngs::StdRng;
+use std::path::{Path, PathBuf};
+
+use crate::files;
+
+pub const PALETTE_TRAIT: &str = "Background Palette";
+pub const BACKGROUNDS_DIR: &str = "backgrounds";
+
+// Background variants drawn in-process instead of from an image.
+#[derive(Debug, Clone, Copy)]
+pub enum Kind {
+    Gradient,
+    Stripes,
+    Starfield,
+}
+
+impl Kind {
+    // Variant names of the kinds, which the catalog file may add to the
+    // background tiers.
+    pub const VARIANTS: [&'static str; 3] =
+        ["NFT_BG_Gradient", "NFT_BG_Stripes", "NFT_BG_Starfield"];
+
+    pub fn of(variant: &str) -> Option<Kind> {
+        match variant {
+            "NFT_BG_Gradient" => Some(Kind::Gradient),
+            "NFT_BG_Stripes" => Some(Kind::Stripes),
+            "NFT_BG_Starfield" => Some(Kind::Starfield),
+            _ => None,
+        }
+    }
+}
+
+struct Palette {
+    name: &'static str,
+    colours: [[f64; 3]; 3],
+}
+
+const PALETTES: [Palette; 5] = [
+    Palette {
+        name: "Nebula",
+        colours: [
+            [38.0, 12.0, 74.0],
+            [139.0, 44.0, 160.0],
+            [244.0, 114.0, 182.0],
+        ],
+    },
+    Palette {
+        name: "Sunset",
+        colours: [
+            [255.0, 94.0, 77.0],
+            [255.0, 155.0, 84.0],
+            [255.0, 221.0, 148.0],
+        ],
+    },
+    Palette {
+        name: "Ocean",
+        colours: [
+            [2.0, 36.0, 72.0],
+            [0.0, 119.0, 182.0],
+            [144.0, 224.0, 239.0],
+        ],
+    },
+    Palette {
+        name: "Aurora",
+        colours: [
+            [10.0, 25.0, 47.0],
+            [46.0, 196.0, 182.0],
+            [173.0, 255.0, 143.0],
+        ],
+    },
+    Palette {
+        name: "Ember",
+        colours: [[30.0, 6.0, 6.0], [156.0, 28.0, 20.0], [255.0, 140.0, 0.0]],
+    },
+];
+
+// Every parameter of a background comes from the seed of its metronion, so
+// it is drawn the same in every render of that id.
+fn rng(id: usize) -> StdRng {
+    // splitmix64 spreads consecutive ids over the seed space
+    let mut seed = (id as u64).wrapping_add(0x9e37_79b9_7f4a_7c15);
+    seed = (seed ^ (seed >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    seed = (seed ^ (seed >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    StdRng::seed_from_u64(seed ^ (seed >> 31))
+}
+
+fn palette(rng: &mut StdRng) -> &'static Palette {
+    PALETTES.choose(rng).expect("No palette")
+}
+
+pub fn palette_name(id: usize) -> &'static str {
+    palette(&mut rng(id)).name
+}
+
+// Draw the background of metronion `id` as an image the size of the other
+// layers, into `<output>/backgrounds/<id - 1>.png`.
+pub fn render(
+    kind: Kind,
+    id: usize,
+    size: (u32, u32),
+    output_dir: &Path,
+) -> std::result::Result<PathBuf, String> {
+    let dir = output_dir.join(BACKGROUNDS_DIR);
+    std::fs::create_dir_all(&dir).map_err(|err| format!("{dir:?}: {err}"))?;
+    let output_file = dir.join(format!("{}.png", id - 1));
+
+    let mut png = vec![];
+    let mut encoder = png::Encoder::new(&mut png, size.0, size.1);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder
+        .write_header()
+        .and_then(|mut writer| writer.write_image_data(&pixels(kind, id, size)))
+        .map_err(|err| format!("{output_file:?}: {err}"))?;
+
+    files::write_atomic(&output_file, &png).map_err(|err| err.to_string())?;
+    Ok(output_file)
+}
+
+fn pixels(kind: Kind, id: usize, (width, height): (u32, u32)) -> Vec<u8> {
+    let mut rng = rng(id);
+    let palette = palette(&mut rng);
+    let (width, height) = (width as usize, height as usize);
+    let mut pixels = vec![[0.0; 3]; width * height];
+
+    match kind {
+        Kind::Gradient => {
+            let angle = rng.gen_range(0.0..std::f64::consts::TAU);
+            fill_gradient(&mut pixels, width, height, angle, &palette.colours);
+        }
+        Kind::Stripes => {
+            let angle = rng.gen_range(0.0..std::f64::consts::PI);
+            fill_gradient(&mut pixels, width, height, angle, &palette.colours);
+            let stripe = rng.gen_range(0.02..0.08) * width.max(1) as f64;
+            let (sin, cos) = (angle + std::f64::consts::FRAC_PI_2).sin_cos();
+            for (i, pixel) in pixels.iter_mut().enumerate() {
+                let (x, y) = ((i % width) as f64, (i / width) as f64);
+                if ((x * cos + y * sin) / stripe).rem_euclid(2.0) < 1.0 {
+                    *pixel = mix(*pixel, palette.colours[2], 0.35);
+                }
+            }
+        }
+        Kind::Starfield => {
+            let night = palette
+                .colours
+                .map(|colour| colour.map(|value| value * 0.25));
+            fill_gradient(
+                &mut pixels,
+                width,
+                height,
+                std::f64::consts::FRAC_PI_2,
+                &night,
+            );
+            let stars = width * height / rng.gen_range(800..2000);
+            for _ in 0..stars {
+                let (x, y) = (rng.gen_range(0..width), rng.gen_range(0..height));
+                let glow = rng.gen_range(0.4..1.0);
+                let star = mix([255.0; 3], palette.colours[2], rng.gen_range(0.0..0.5));
+                let radius = if rng.gen_bool(0.1) { 1 } else { 0 };
+                for y in y.saturating_sub(radius)..(y + radius + 1).min(height) {
+                    for x in x.saturating_sub(radius)..(x + radius + 1).min(width) {
+                        let pixel = &mut pixels[y * width + x];
+                        *pixel = mix(*pixel, star, glow);
+                    }
+                }
+            }
+        }
+    }
+
+    pixels
+        .into_iter()
+        .flat_map(|pixel| pixel.map(|value| value.round().clamp(0.0, 255.0) as u8))
+        .collect()
+}
+
+// Linear gradient through the three colours along `angle`.
+fn fill_gradient(
+    pixels: &mut [[f64; 3]],
+    width: usize,
+    height: usize,
+    angle: f64,
+    colours: &[[f64; 3]; 3],
+) {
+    let (sin, cos) = angle.sin_cos();
+    let extent = (width as f64 * cos).abs() + (height as f64 * sin).abs();
+    let (centre_x, centre_y) = (width as f64 / 2.0, height as f64 / 2.0);
+    for (i, pixel) in pixels.iter_mut().enumerate() {
+        let (x, y) = ((i % width) as f64 - centre_x, (i / width) as f64 - centre_y);
+        let t = ((x * cos + y * sin) / extent.max(1.0) + 0.5).clamp(0.0, 1.0);
+        *pixel = if t < 0.5 {
+            mix(colours[0], colours[1], t * 2.0)
+        } else {
+            mix(colours[1], colours[2], t * 2.0 - 1.0)
+        };
+    }
+}
+
+fn mix(from: [f64; 3], to: [f64; 3], amount: f64) -> [f64; 3] {
+    [0, 1, 2].map(|channel| from[channel] + (to[channel] - from[channel]) * amount)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn backgrounds_are_drawn_the_same_for_an_id() {
+        for variant in Kind::VARIANTS {
+            let kind = Kind::of(variant).expect("Procedural background");
+            assert_eq!(pixels(kind, 7, (64, 64)), pixels(kind, 7, (64, 64)));
+            assert_ne!(pixels(kind, 7, (64, 64)), pixels(kind, 8, (64, 64)));
+        }
+        assert_eq!(palette_name(7), palette_name(7));
+    }
+
+    #[test]
+    fn ids_spread_over_every_palette() {
+        let names = (1..=100).map(palette_name).collect::<HashSet<_>>();
+
+        assert_eq!(names.len(), PALETTES.len());
+    }
+}
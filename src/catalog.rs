@@ -0,0 +1,206 @@
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use crate::error::{Error, IoContext, Result};
+use crate::render::{Blend, Recolour};
+use crate::{Gender, Rarity};
+
+// Variants of several layers designed as one outfit, forced together when
+// the set is rolled.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Set {
+    pub name: String,
+    pub rarity: Rarity,
+    // variant per layer name
+    pub layers: BTreeMap<String, String>,
+}
+
+impl Set {
+    pub fn variant(&self, layer: &str) -> Option<&str> {
+        self.layers.get(layer).map(String::as_str)
+    }
+}
+
+// Where a variant is drawn instead of at its layer in the layer order, e.g.
+// glasses over the hair.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Placement {
+    // at this position of the layer order, before the layer there
+    ZIndex(usize),
+    Above(String),
+    Below(String),
+}
+
+// A variant drawn from the art of a built-in variant of the same layer,
+// recoloured at render time.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ColourVariant {
+    pub base: String,
+    pub rarity: Rarity,
+    pub recolour: Recolour,
+}
+
+// A layer drawn as another image part of the trait of the layer it follows,
+// e.g. the front hair with the long hair, its variant paired with the
+// followed one.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Link {
+    pub follows: String,
+    // linked variant per variant of the followed layer
+    pub pairs: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Sets {
+    #[serde(default)]
+    boy: Vec<Set>,
+    #[serde(default)]
+    girl: Vec<Set>,
+}
+
+// Reviewed additions to the built-in catalog, loaded from a JSON file such
+// as
+//
+//   {
+//     "sets": {
+//       "boy": [
+//         {
+//           "name": "Cyberpunk",
+//           "rarity": "Legendary",
+//           "layers": { "Hand": "NFT_Hand_9", "Clothes": "NFT_B_Clothes_18" }
+//         }
+//       ]
+//     },
+//     "placements": { "NFT_Face_Acc_7": { "above": "Hair" } },
+//     "blends": { "NFT_Head_Phone_5": { "mode": "add", "offset": [0, -4] } },
+//     "colour_variants": {
+//       "NFT_B_Clothes_3_Red": {
+//         "base": "NFT_B_Clothes_3",
+//         "rarity": "Rare",
+//         "recolour": { "shift": { "hue": -40, "saturation": 130 } }
+//       }
+//     },
+//     "procedural_backgrounds": { "NFT_BG_Gradient": "Epic" },
+//     "links": {
+//       "Hair": {
+//         "follows": "HairLong",
+//         "pairs": { "NFT_B_Hair_Long_1_Red": "NFT_B_Hair_1_Red" }
+//       }
+//     }
+//   }
+//
+// Without a catalog file, the built-in catalog is used as it is.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Catalog {
+    #[serde(default)]
+    sets: Sets,
+    // placement per variant
+    #[serde(default)]
+    placements: BTreeMap<String, Placement>,
+    // blend per variant, the others are drawn normally
+    #[serde(default)]
+    blends: BTreeMap<String, Blend>,
+    // colour variant per name
+    #[serde(default)]
+    colour_variants: BTreeMap<String, ColourVariant>,
+    // rarity per procedural background, which are left out otherwise
+    #[serde(default)]
+    procedural_backgrounds: BTreeMap<String, Rarity>,
+    // link per layer name, adding to the built-in links
+    #[serde(default)]
+    links: BTreeMap<String, Link>,
+    #[serde(skip)]
+    file: Option<PathBuf>,
+}
+
+impl Catalog {
+    pub fn load(catalog_file: &Path) -> Result<Self> {
+        let contents = std::fs::read(catalog_file).with_path(catalog_file)?;
+        let catalog: Self = serde_json::from_slice(&contents).map_err(|err| Error::Parse {
+            path: catalog_file.to_path_buf(),
+            line: err.line(),
+            message: err.to_string(),
+        })?;
+        Ok(Self {
+            file: Some(catalog_file.to_path_buf()),
+            ..catalog
+        })
+    }
+
+    // The file the catalog was loaded from.
+    pub fn file(&self) -> Option<&Path> {
+        self.file.as_deref()
+    }
+
+    pub fn sets(&self, gender: Gender) -> &[Set] {
+        match gender {
+            Gender::Boy => &self.sets.boy,
+            Gender::Girl => &self.sets.girl,
+        }
+    }
+
+    pub fn placement(&self, variant: &str) -> Option<&Placement> {
+        self.placements.get(variant)
+    }
+
+    pub fn blend(&self, variant: &str) -> Blend {
+        self.blends.get(variant).copied().unwrap_or(Blend::NORMAL)
+    }
+
+    pub fn blends(&self) -> impl Iterator<Item = (&str, &Blend)> {
+        self.blends
+            .iter()
+            .map(|(variant, blend)| (variant.as_str(), blend))
+    }
+
+    pub fn colour_variant(&self, variant: &str) -> Option<&ColourVariant> {
+        self.colour_variants.get(variant)
+    }
+
+    pub fn colour_variants(&self) -> impl Iterator<Item = (&str, &ColourVariant)> {
+        self.colour_variants
+            .iter()
+            .map(|(variant, colour)| (variant.as_str(), colour))
+    }
+
+    pub fn procedural_backgrounds(&self) -> impl Iterator<Item = (&str, Rarity)> {
+        self.procedural_backgrounds
+            .iter()
+            .map(|(variant, rarity)| (variant.as_str(), *rarity))
+    }
+
+    pub fn link(&self, layer: &str) -> Option<&Link> {
+        self.links.get(layer)
+    }
+
+    pub fn links(&self) -> impl Iterator<Item = (&str, &Link)> {
+        self.links
+            .iter()
+            .map(|(layer, link)| (layer.as_str(), link))
+    }
+
+    pub fn placements(&self) -> impl Iterator<Item = (&str, &Placement)> {
+        self.placements
+            .iter()
+            .map(|(variant, placement)| (variant.as_str(), placement))
+    }
+}
+
+static CATALOG: OnceLock<Catalog> = OnceLock::new();
+
+pub fn init(catalog: Catalog) {
+    let _ = CATALOG.set(catalog);
+}
+
+// The catalog file given on the command line, empty otherwise.
+pub fn get() -> &'static Catalog {
+    CATALOG.get_or_init(Catalog::default)
+}
@@ -0,0 +1,73 @@
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use crate::catalog;
+use crate::derivatives::Profile;
+use crate::error::{Error, IoContext, Result};
+use crate::files;
+
+pub const CHECKPOINT_FILE: &str = "checkpoint.json";
+
+// Renders of a run that are not finished yet, keyed by metronion index.
+// It is saved in the output directory before rendering starts and updated
+// when the run is interrupted, so `--resume` renders exactly what is left.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Checkpoint<T> {
+    pub input_dir: PathBuf,
+    pub mapping_file: PathBuf,
+    pub pending: BTreeMap<usize, T>,
+    // resized copies made of every render
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub derivatives: Vec<Profile>,
+    // catalog file the run was started with, which the resume must load too
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub catalog_file: Option<PathBuf>,
+}
+
+impl<T: Serialize + DeserializeOwned> Checkpoint<T> {
+    pub fn path(output_dir: &Path) -> PathBuf {
+        output_dir.join(CHECKPOINT_FILE)
+    }
+
+    pub fn load(output_dir: &Path) -> Result<Self> {
+        let path = Self::path(output_dir);
+        if !path.exists() {
+            return Err(Error::Validation(format!(
+                "nothing to resume, {path:?} does not exist"
+            )));
+        }
+
+        let contents = std::fs::read(&path).with_path(&path)?;
+        let checkpoint: Self = serde_json::from_slice(&contents).map_err(|err| Error::Parse {
+            path: path.clone(),
+            line: err.line(),
+            message: err.to_string(),
+        })?;
+        if checkpoint.catalog_file.as_deref() != catalog::get().file() {
+            let started_with = match &checkpoint.catalog_file {
+                Some(catalog_file) => format!("--catalog {catalog_file:?}"),
+                None => "no --catalog".to_string(),
+            };
+            return Err(Error::Validation(format!(
+                "{path:?} was started with {started_with}, resume it with the same catalog file"
+            )));
+        }
+        Ok(checkpoint)
+    }
+
+    pub fn save(&self, output_dir: &Path) -> Result<PathBuf> {
+        let path = Self::path(output_dir);
+        files::write_json_atomic(&path, self)?;
+        Ok(path)
+    }
+
+    pub fn remove(output_dir: &Path) -> Result<()> {
+        let path = Self::path(output_dir);
+        if path.exists() {
+            std::fs::remove_file(&path).with_path(&path)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,199 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::path::Path;
+
+use crate::background::BACKGROUNDS_DIR;
+use crate::error::{Error, IoContext, Result};
+use crate::frames::FRAMED_DIR;
+use crate::metadata::METADATA_DIR;
+use crate::render;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Format {
+    Png,
+    Webp,
+    Jpeg,
+}
+
+impl Format {
+    fn extension(&self) -> &'static str {
+        match self {
+            Format::Png => "png",
+            Format::Webp => "webp",
+            Format::Jpeg => "jpg",
+        }
+    }
+}
+
+// A resized copy of every rendered metronion, written to
+// `<output>/<name>/<index>.<extension>`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Profile {
+    pub name: String,
+    // longest side in pixels, the aspect ratio is kept
+    pub size: u32,
+    pub format: Format,
+    // lossy quality from 1 to 100, magick's default when not given
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quality: Option<u8>,
+}
+
+impl Profile {
+    // Path of the derivative of metronion `index`, relative to the output
+    // directory.
+    pub fn image(&self, index: usize) -> String {
+        format!("{}/{index}.{}", self.name, self.format.extension())
+    }
+}
+
+// Derivative profiles loaded from a JSON file such as
+//
+//   [
+//     { "name": "master", "size": 2048, "format": "png" },
+//     { "name": "web", "size": 512, "format": "webp", "quality": 90 },
+//     { "name": "thumbnail", "size": 128, "format": "jpeg", "quality": 85 }
+//   ]
+pub fn load(profiles_file: &Path) -> Result<Vec<Profile>> {
+    let contents = std::fs::read(profiles_file).with_path(profiles_file)?;
+    let profiles: Vec<Profile> = serde_json::from_slice(&contents).map_err(|err| Error::Parse {
+        path: profiles_file.to_path_buf(),
+        line: err.line(),
+        message: err.to_string(),
+    })?;
+
+    let mut names = HashSet::new();
+    for profile in &profiles {
+        let name = profile.name.as_str();
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        {
+            return Err(Error::Validation(format!(
+                "{profiles_file:?}: profile name {name:?} must be letters, digits, '_' or '-'"
+            )));
+        }
+        if [METADATA_DIR, BACKGROUNDS_DIR, FRAMED_DIR].contains(&name) {
+            return Err(Error::Validation(format!(
+                "{profiles_file:?}: profile name {name:?} is used by another output"
+            )));
+        }
+        if !names.insert(name) {
+            return Err(Error::Validation(format!(
+                "{profiles_file:?}: profile {name:?} is given twice"
+            )));
+        }
+        if profile.size == 0 {
+            return Err(Error::Validation(format!(
+                "{profiles_file:?}: profile {name:?} must have a size of at least 1"
+            )));
+        }
+        if let Some(quality) = profile
+            .quality
+            .filter(|quality| !(1..=100).contains(quality))
+        {
+            return Err(Error::Validation(format!(
+                "{profiles_file:?}: profile {name:?} has quality {quality}, expected 1 to 100"
+            )));
+        }
+    }
+
+    Ok(profiles)
+}
+
+// Resample the rendered `image` of metronion `index` into every profile.
+pub fn render(
+    profiles: &[Profile],
+    image: &Path,
+    output_dir: &Path,
+    index: usize,
+) -> std::result::Result<(), String> {
+    for profile in profiles {
+        let output_file = output_dir.join(profile.image(index));
+        if let Some(dir) = output_file.parent() {
+            std::fs::create_dir_all(dir).map_err(|err| format!("{dir:?}: {err}"))?;
+        }
+
+        let size = format!("{0}x{0}", profile.size);
+        let mut args = vec![OsString::from("convert"), image.as_os_str().to_owned()];
+        args.extend(["-filter", "Lanczos", "-resize", &size].map(OsString::from));
+        if profile.format == Format::Jpeg {
+            // JPEG has no transparency
+            args.extend(["-background", "white", "-alpha", "remove"].map(OsString::from));
+        }
+        if let Some(quality) = profile.quality {
+            args.extend(["-quality".into(), quality.to_string().into()]);
+        }
+        args.push("-strip".into());
+        render::magick(args, &output_file)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::files;
+
+    fn load_profiles(name: &str, json: &str) -> Result<Vec<Profile>> {
+        let profiles_file = files::test_dir(name).join("derivatives.json");
+        std::fs::write(&profiles_file, json).expect("Test profiles");
+        load(&profiles_file)
+    }
+
+    #[test]
+    fn load_reads_the_profiles() {
+        let profiles = load_profiles(
+            "derivatives-valid",
+            r#"[
+                { "name": "master", "size": 2048, "format": "png" },
+                { "name": "web", "size": 512, "format": "webp", "quality": 90 }
+            ]"#,
+        )
+        .expect("Valid profiles");
+
+        assert_eq!(profiles.len(), 2);
+        assert_eq!(profiles[0].quality, None);
+        assert_eq!(profiles[1].image(4), "web/4.webp");
+    }
+
+    #[test]
+    fn load_rejects_invalid_profiles() {
+        for (name, json) in [
+            (
+                "path",
+                r#"[{ "name": "../web", "size": 512, "format": "png" }]"#,
+            ),
+            (
+                "output",
+                r#"[{ "name": "metadata", "size": 512, "format": "png" }]"#,
+            ),
+            (
+                "twice",
+                r#"[
+                    { "name": "web", "size": 512, "format": "png" },
+                    { "name": "web", "size": 256, "format": "webp" }
+                ]"#,
+            ),
+            ("size", r#"[{ "name": "web", "size": 0, "format": "png" }]"#),
+            (
+                "quality",
+                r#"[{ "name": "web", "size": 512, "format": "jpeg", "quality": 0 }]"#,
+            ),
+        ] {
+            let result = load_profiles(&format!("derivatives-{name}"), json);
+            assert!(
+                matches!(result, Err(Error::Validation(_))),
+                "{name} should be rejected"
+            );
+        }
+
+        let result = load_profiles(
+            "derivatives-format",
+            r#"[{ "name": "web", "size": 512, "format": "gif" }]"#,
+        );
+        assert!(matches!(result, Err(Error::Parse { .. })));
+    }
+}
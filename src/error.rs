@@ -0,0 +1,78 @@
+use std::path::{Path, PathBuf};
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("{path:?}: {source}")]
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[error("invalid catalog: {0}")]
+    Catalog(String),
+
+    #[error("{path:?} line {line}: {message}")]
+    Parse {
+        path: PathBuf,
+        line: usize,
+        message: String,
+    },
+
+    #[error("metronion {token_id}: {message}")]
+    Render { token_id: usize, message: String },
+
+    #[error("{count} renders failed, see {report:?}")]
+    RenderFailures { count: usize, report: PathBuf },
+
+    #[error("{0}")]
+    Validation(String),
+
+    #[error("{}", cancelled_message(checkpoint))]
+    Cancelled { checkpoint: Option<PathBuf> },
+}
+
+fn cancelled_message(checkpoint: &Option<PathBuf>) -> String {
+    match checkpoint {
+        Some(checkpoint) => format!(
+            "interrupted, progress saved to {checkpoint:?}, rerun with --resume to continue"
+        ),
+        None => "interrupted, rerun to complete the remaining metronions".to_string(),
+    }
+}
+
+impl Error {
+    // Exit codes follow sysexits.h so scripts can tell failure kinds apart.
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            Error::Validation(_) => 64,
+            Error::Parse { .. } => 65,
+            Error::RenderFailures { .. } | Error::Render { .. } => 70,
+            Error::Io { .. } => 74,
+            Error::Catalog(_) => 78,
+            // 128 + SIGINT, like a shell reports an interrupted command
+            Error::Cancelled { .. } => 130,
+        }
+    }
+}
+
+// Attach the offending path to I/O errors.
+pub trait IoContext<T> {
+    fn with_path(self, path: impl AsRef<Path>) -> Result<T>;
+}
+
+impl<T> IoContext<T> for std::io::Result<T> {
+    fn with_path(self, path: impl AsRef<Path>) -> Result<T> {
+        self.map_err(|source| Error::Io {
+            path: path.as_ref().to_path_buf(),
+            source,
+        })
+    }
+}
+
+impl<T> IoContext<T> for serde_json::Result<T> {
+    fn with_path(self, path: impl AsRef<Path>) -> Result<T> {
+        self.map_err(std::io::Error::from).with_path(path)
+    }
+}
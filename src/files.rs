@@ -0,0 +1,163 @@
+use serde::Serialize;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::error::{IoContext, Result};
+
+const PARTIAL_MARKER: &str = ".partial";
+
+// Outputs are written next to their final path first and renamed once
+// complete, so an interrupted run never leaves a truncated file behind.
+// The extension is kept so that magick still infers the image format.
+pub fn partial_path(path: &Path) -> PathBuf {
+    let stem = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_default();
+
+    match path.extension() {
+        Some(extension) => path.with_file_name(format!(
+            "{stem}{PARTIAL_MARKER}.{}",
+            extension.to_string_lossy()
+        )),
+        None => path.with_file_name(format!("{stem}{PARTIAL_MARKER}")),
+    }
+}
+
+pub fn write_atomic(path: &Path, contents: &[u8]) -> Result<()> {
+    let partial = partial_path(path);
+
+    let mut file = std::fs::File::create(&partial).with_path(&partial)?;
+    file.write_all(contents).with_path(&partial)?;
+    file.sync_all().with_path(&partial)?;
+
+    std::fs::rename(&partial, path).with_path(path)
+}
+
+pub fn write_json_atomic<T: Serialize + ?Sized>(path: &Path, value: &T) -> Result<()> {
+    let contents = serde_json::to_vec_pretty(value).with_path(path)?;
+    write_atomic(path, &contents)
+}
+
+pub fn copy_atomic(from: &Path, to: &Path) -> Result<()> {
+    let contents = std::fs::read(from).with_path(from)?;
+    write_atomic(to, &contents)
+}
+
+// Remove leftovers of writes that were interrupted, returning how many
+// files were removed.
+pub fn remove_partials(dir: &Path) -> Result<usize> {
+    let mut removed = 0;
+    for entry in std::fs::read_dir(dir).with_path(dir)? {
+        let path = entry.with_path(dir)?.path();
+        if path.is_dir() {
+            removed += remove_partials(&path)?;
+        } else if path
+            .file_name()
+            .is_some_and(|name| name.to_string_lossy().contains(PARTIAL_MARKER))
+        {
+            std::fs::remove_file(&path).with_path(&path)?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+// Suffix shared by the backups taken during one run, e.g.
+// `mapping_boy.txt.bak-20240131T235959`.
+pub fn backup_stamp() -> String {
+    chrono::Local::now().format("%Y%m%dT%H%M%S").to_string()
+}
+
+fn backup_path(path: &Path, stamp: &str) -> PathBuf {
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+
+    let mut backup = path.with_file_name(format!("{name}.bak-{stamp}"));
+    let mut counter = 1;
+    while backup.exists() {
+        backup = path.with_file_name(format!("{name}.bak-{stamp}-{counter}"));
+        counter += 1;
+    }
+    backup
+}
+
+// Move a file or directory that is about to be replaced out of the way.
+pub fn backup(path: &Path, stamp: &str) -> Result<PathBuf> {
+    let backup = backup_path(path, stamp);
+    std::fs::rename(path, &backup).with_path(path)?;
+    Ok(backup)
+}
+
+// Copy a file that is about to be modified in place.
+pub fn backup_copy(path: &Path, stamp: &str) -> Result<PathBuf> {
+    let backup = backup_path(path, stamp);
+    std::fs::copy(path, &backup).with_path(path)?;
+    Ok(backup)
+}
+
+pub fn is_empty_dir(dir: &Path) -> Result<bool> {
+    if !dir.exists() {
+        return Ok(true);
+    }
+    Ok(std::fs::read_dir(dir).with_path(dir)?.next().is_none())
+}
+
+// A fresh directory for the files of one test.
+#[cfg(test)]
+pub fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("metronions-{}-{name}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).expect("Test directory");
+    dir
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn partial_path_keeps_the_extension() {
+        assert_eq!(
+            partial_path(Path::new("output/12.png")),
+            Path::new("output/12.partial.png")
+        );
+        assert_eq!(
+            partial_path(Path::new("mapping_boy")),
+            Path::new("mapping_boy.partial")
+        );
+    }
+
+    #[test]
+    fn remove_partials_leaves_the_complete_files() {
+        let dir = test_dir("files-partials");
+        std::fs::create_dir_all(dir.join("metadata")).expect("Test directory");
+        write_atomic(&dir.join("0.png"), b"done").expect("Written image");
+        for partial in ["1.partial.png", "metadata/1.partial.json"] {
+            std::fs::write(dir.join(partial), "").expect("Test partial");
+        }
+
+        assert_eq!(remove_partials(&dir).expect("Removed partials"), 2);
+        assert_eq!(std::fs::read(dir.join("0.png")).expect("Image"), b"done");
+        assert!(is_empty_dir(&dir.join("metadata")).expect("Metadata directory"));
+    }
+    #[test]
+    fn backups_of_one_run_do_not_overwrite_each_other() {
+        let dir = test_dir("files-backups");
+        let mapping_file = dir.join("mapping_boy.txt");
+        std::fs::write(&mapping_file, "first").expect("Test mapping");
+        let stamp = "20240131T235959";
+
+        let copy = backup_copy(&mapping_file, stamp).expect("Copied backup");
+        let moved = backup(&mapping_file, stamp).expect("Moved backup");
+
+        assert_eq!(copy, dir.join("mapping_boy.txt.bak-20240131T235959"));
+        assert_eq!(moved, dir.join("mapping_boy.txt.bak-20240131T235959-1"));
+        assert!(!mapping_file.exists());
+        for backup in [copy, moved] {
+            assert_eq!(std::fs::read(backup).expect("Backup"), b"first");
+        }
+    }
+}
@@ -0,0 +1,111 @@
+use std::ffi::OsString;
+use std::path::Path;
+
+use crate::error::{Error, IoContext, Result};
+use crate::progress::Progress;
+use crate::render::{self, RenderFailure};
+use crate::shutdown;
+
+pub const FRAMED_DIR: &str = "framed";
+
+// How the metronions of a rarity tier are framed.
+#[derive(Debug, Clone, Copy)]
+pub struct Frame {
+    pub colour: &'static str,
+    // border width in thousandths of the image width
+    pub width: u32,
+    // colour of the badge in the top right corner
+    pub badge: Option<&'static str>,
+}
+
+// Draw the frame of every metronion over its image in `output_dir` into
+// `<output>/framed/<index>.png`, leaving the image itself untouched. The
+// `failures` of metronions left out beforehand are reported with the render
+// failures.
+pub async fn frame_metronions(
+    tokens: Vec<(usize, Vec<String>, Frame)>,
+    mut failures: Vec<RenderFailure>,
+    output_dir: &Path,
+    jobs: usize,
+) -> Result<()> {
+    info!("Number of framed metronions = {:?}", tokens.len());
+
+    let framed_dir = output_dir.join(FRAMED_DIR);
+    std::fs::create_dir_all(&framed_dir).with_path(&framed_dir)?;
+
+    let progress = Progress::new("frame", tokens.len());
+    let tasks = tokens.into_iter().map(|(index, parts, frame)| {
+        let task = tokio::task::spawn_blocking({
+            let image = output_dir.join(format!("{index:}.png"));
+            let output_file = framed_dir.join(format!("{index:}.png"));
+            let parts = parts.clone();
+            move || match render::with_retries(index + 1, &parts, || {
+                frame_image(&image, &output_file, frame)
+            }) {
+                Ok(()) => vec![],
+                Err(failure) => vec![failure],
+            }
+        });
+        render::join_render(task, index, parts)
+    });
+
+    let report = render::collect(tasks, jobs, &progress).await;
+    if shutdown::is_cancelled() {
+        return Err(Error::Cancelled { checkpoint: None });
+    }
+    failures.extend(report.failures);
+    failures.sort_by_key(|failure| failure.token_id);
+    render::write_failures(&framed_dir, &failures)
+}
+
+fn frame_image(image: &Path, output_file: &Path, frame: Frame) -> std::result::Result<(), String> {
+    let (width, _) = render::image_size(image)?;
+    let border = (width * frame.width / 1000).max(1);
+
+    // the border replaces the edge of the image so the size stays the same
+    let mut args = vec![OsString::from("convert"), image.as_os_str().to_owned()];
+    args.extend(
+        [
+            "-shave",
+            &format!("{border}x{border}"),
+            "-bordercolor",
+            frame.colour,
+            "-border",
+            &format!("{border}x{border}"),
+        ]
+        .map(OsString::from),
+    );
+
+    if let Some(badge) = frame.badge {
+        let size = (width / 8).max(8);
+        let stroke = (size / 16).max(1);
+        let centre = size / 2;
+        args.extend(
+            [
+                "(",
+                "-size",
+                &format!("{size}x{size}"),
+                "xc:none",
+                "-fill",
+                badge,
+                "-stroke",
+                frame.colour,
+                "-strokewidth",
+                &stroke.to_string(),
+                "-draw",
+                &format!("circle {centre},{centre} {centre},{stroke}"),
+                ")",
+                "-gravity",
+                "NorthEast",
+                "-geometry",
+                &format!("+{0}+{0}", border * 2),
+                "-compose",
+                "Over",
+                "-composite",
+            ]
+            .map(OsString::from),
+        );
+    }
+
+    render::magick(args, output_file)
+}
@@ -0,0 +1,83 @@
+use serde::Serialize;
+use std::fmt::Arguments;
+use std::io::Write;
+use std::sync::OnceLock;
+
+use crate::progress;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Level {
+    Info,
+    Warn,
+    Error,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Output {
+    format: LogFormat,
+    quiet: bool,
+}
+
+static OUTPUT: OnceLock<Output> = OnceLock::new();
+
+// Until the command line is parsed, log as text.
+fn output() -> Output {
+    OUTPUT.get().copied().unwrap_or(Output {
+        format: LogFormat::Text,
+        quiet: false,
+    })
+}
+
+pub fn init(format: LogFormat, quiet: bool) {
+    let _ = OUTPUT.set(Output { format, quiet });
+}
+
+pub fn format() -> LogFormat {
+    output().format
+}
+
+pub fn is_quiet() -> bool {
+    output().quiet
+}
+
+#[derive(Serialize)]
+struct LogEvent<'a> {
+    event: &'static str,
+    level: Level,
+    message: &'a str,
+}
+
+pub fn log(level: Level, args: Arguments) {
+    let output = output();
+    if output.quiet && level == Level::Info {
+        return;
+    }
+
+    match output.format {
+        LogFormat::Text => progress::suspend(|| match level {
+            Level::Info => paris::info!("{args}"),
+            Level::Warn => paris::warn!("{args}"),
+            Level::Error => paris::error!("{args}"),
+        }),
+        LogFormat::Json => json_line(&LogEvent {
+            event: "log",
+            level,
+            message: &args.to_string(),
+        }),
+    }
+}
+
+// Write one JSON object per line on stdout.
+pub fn json_line<T: Serialize>(event: &T) {
+    if let Ok(line) = serde_json::to_string(event) {
+        let mut stdout = std::io::stdout().lock();
+        let _ = writeln!(stdout, "{line}");
+    }
+}
@@ -1,23 +1,79 @@
-use clap::{arg, command, value_parser, ArgAction, Command};
-use futures::stream::StreamExt;
-use paris::{error, info};
-use rand::distributions::WeightedIndex;
+// Logging macros honouring --quiet and --log-format, declared before the
+// modules so that all of them can use them.
+macro_rules! info {
+    ($($arg:tt)*) => {
+        $crate::log::log($crate::log::Level::Info, format_args!($($arg)*))
+    };
+}
+
+macro_rules! warn {
+    ($($arg:tt)*) => {
+        $crate::log::log($crate::log::Level::Warn, format_args!($($arg)*))
+    };
+}
+
+macro_rules! error {
+    ($($arg:tt)*) => {
+        $crate::log::log($crate::log::Level::Error, format_args!($($arg)*))
+    };
+}
+
+mod atlas;
+mod background;
+mod catalog;
+mod checkpoint;
+mod derivatives;
+mod error;
+mod files;
+mod frames;
+mod log;
+mod mapping;
+mod metadata;
+mod pinned;
+mod progress;
+mod render;
+mod rules;
+mod shutdown;
+mod simulate;
+mod solver;
+mod stats;
+mod stickers;
+mod stratify;
+
+use clap::{arg, command, value_parser, Arg, ArgAction, ArgMatches, Command};
 use rand::prelude::*;
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fmt::Display;
-use std::fs::{File, OpenOptions};
-use std::io::{BufRead, BufReader, Write};
 use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::OnceLock;
+
+use crate::catalog::Placement;
+use crate::checkpoint::Checkpoint;
+use crate::error::{Error, IoContext, Result};
+use crate::log::LogFormat;
+use crate::metadata::Metadata;
+use crate::pinned::{Pin, PinnedToken};
+use crate::progress::Progress;
+use crate::render::{LayerImage, Recolour, RenderFailure};
+use crate::rules::Rules;
 
 const TOTAL_BOYS: usize = 5000;
 const TOTAL_GIRLS: usize = 5000;
 
-const BUFFER_SIZE: usize = 1000;
-
 #[tokio::main]
-async fn main() {
-    info!("Starting...");
+async fn main() -> ExitCode {
+    match run().await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            error!("{err}");
+            ExitCode::from(err.exit_code())
+        }
+    }
+}
 
+async fn run() -> Result<()> {
     // parse cli
     let matches = command!()
         .subcommand(
@@ -38,6 +94,195 @@ async fn main() {
                         .value_parser(value_parser!(usize)),
                 ),
         )
+        .subcommand(
+            Command::new("export-stickers")
+                .about("Export emotion stickers and emoji for messaging apps")
+                .arg(
+                    arg!(--from <FILE> "From mapping file")
+                        .required(true)
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    arg!(--"input-dir" <DIR> "Input directory path")
+                        .required(true)
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    arg!(--"output-dir" <DIR> "Output directory path")
+                        .required(false)
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    arg!(--"from-index" <INDEX> "From index")
+                        .required(false)
+                        .value_parser(value_parser!(usize)),
+                )
+                .arg(
+                    arg!(--name <NAME> "Sticker pack name")
+                        .required(false)
+                        .default_value("Metronions"),
+                )
+                .arg(
+                    arg!(--"max-bytes" <BYTES> "Maximum size of a single sticker file")
+                        .required(false)
+                        .default_value("102400")
+                        .value_parser(value_parser!(u64)),
+                )
+                .arg(
+                    arg!(--"emoji-max-bytes" <BYTES> "Maximum size of a single emoji file")
+                        .required(false)
+                        .default_value("16384")
+                        .value_parser(value_parser!(u64)),
+                ),
+        )
+        .subcommand(
+            Command::new("pack-emotions")
+                .about("Pack the emotion frames of each metronion into a sprite sheet and atlas")
+                .arg(
+                    arg!(--"emotions-dir" <DIR> "Emotions directory path")
+                        .required(true)
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    arg!(--"output-dir" <DIR> "Output directory path")
+                        .required(false)
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    arg!(--columns <COLUMNS> "Number of frames per sprite sheet row")
+                        .required(false)
+                        .default_value("5")
+                        .value_parser(value_parser!(usize)),
+                ),
+        )
+        .subcommand(
+            Command::new("frame")
+                .about("Frame the rendered metronions by rarity into separate images")
+                .arg(
+                    arg!(--from <FILE> "From mapping file")
+                        .required(true)
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    arg!(--"output-dir" <DIR> "Directory of the rendered metronions")
+                        .required(false)
+                        .default_value("output")
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    arg!(--"min-rarity" <RARITY> "Only frame metronions at least this rare")
+                        .required(false)
+                        .default_value("Common")
+                        .value_parser(Rarity::ALL.map(|rarity| rarity.name())),
+                ),
+        )
+        .subcommand(
+            Command::new("stats")
+                .about("Count the distinct metronions and the exact probability of every variant")
+                .arg(
+                    arg!(--gender <GENDER> "Gender of the catalog, both by default")
+                        .required(false)
+                        .value_parser(["boy", "girl"]),
+                )
+                .arg(
+                    arg!(--count <COUNT> "Collection size to compute the expected supply for, defaults to the gender supply")
+                        .required(false)
+                        .value_parser(value_parser!(usize)),
+                )
+                .arg(
+                    arg!(--output <FILE> "Write the statistics as JSON to this file")
+                        .required(false)
+                        .value_parser(value_parser!(PathBuf)),
+                ),
+        )
+        .subcommand(
+            Command::new("simulate")
+                .about("Sample many collections to see how much the supply of each variant varies")
+                .arg(
+                    arg!(--gender <GENDER> "Gender of the catalog, both by default")
+                        .required(false)
+                        .value_parser(["boy", "girl"]),
+                )
+                .arg(
+                    arg!(--count <COUNT> "Size of each simulated collection, defaults to the gender supply")
+                        .required(false)
+                        .value_parser(value_parser!(usize)),
+                )
+                .arg(
+                    arg!(--runs <RUNS> "Number of simulated collections")
+                        .required(false)
+                        .default_value("1000")
+                        .value_parser(value_parser!(usize)),
+                )
+                .arg(
+                    arg!(--output <FILE> "Write the simulation as JSON to this file")
+                        .required(false)
+                        .value_parser(value_parser!(PathBuf)),
+                ),
+        )
+        .subcommand(
+            Command::new("extend")
+                .about("Add new metronions after the highest id of an existing mapping")
+                .arg(
+                    arg!(--from <FILE> "From mapping file")
+                        .required(true)
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    arg!(--"input-dir" <DIR> "Input directory path")
+                        .required(true)
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    arg!(--"output-dir" <DIR> "Output directory path")
+                        .required(false)
+                        .default_value("output")
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    arg!(--count <COUNT> "Number of metronions to add")
+                        .required(true)
+                        .value_parser(value_parser!(usize)),
+                )
+                .arg(
+                    arg!(--"max-supply" <COUNT> "Maximum size of the collection, defaults to the gender supply")
+                        .required(false)
+                        .value_parser(value_parser!(usize)),
+                )
+                .args(generator_args()),
+        )
+        .subcommand(
+            Command::new("reroll")
+                .about("Regenerate rejected metronions, keeping the rest of the collection")
+                .arg(
+                    arg!(--from <FILE> "From mapping file")
+                        .required(true)
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    arg!(--"input-dir" <DIR> "Input directory path")
+                        .required(true)
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    arg!(--"output-dir" <DIR> "Output directory path")
+                        .required(false)
+                        .default_value("output")
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    arg!(--ids <IDS> "Comma separated metronion ids to regenerate")
+                        .required(false)
+                        .value_delimiter(',')
+                        .value_parser(value_parser!(usize)),
+                )
+                .arg(
+                    arg!(--"ids-file" <FILE> "Rejection list with one or more metronion ids per line")
+                        .required(false)
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .args(generator_args()),
+        )
         .arg(
             arg!(--"input-dir" <DIR> "Input directory path")
                 .required(false)
@@ -50,12 +295,88 @@ async fn main() {
                 .value_parser(value_parser!(PathBuf)),
         )
         .arg(
-            arg!(--reset <BOOL> "Reset generation")
+            arg!(--overwrite "Replace an existing mapping, backing up the old mapping and output")
+                .required(false)
+                .alias("reset")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            arg!(--"append-continue-ids" "Append to an existing mapping, numbering after its highest id")
+                .required(false)
+                .conflicts_with("overwrite")
+                .action(ArgAction::SetTrue),
+        )
+        .args(generator_args())
+        .arg(
+            arg!(--catalog <FILE> "JSON file of reviewed catalog additions such as outfit sets")
+                .required(false)
+                .global(true)
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            arg!(--jobs <JOBS> "Number of images rendered in parallel, defaults to the CPU count")
+                .required(false)
+                .global(true)
+                .value_parser(value_parser!(usize)),
+        )
+        .arg(
+            arg!(--resume "Resume the interrupted run whose checkpoint is in the output directory")
+                .required(false)
+                .global(true)
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            arg!(--quiet "Only print warnings and errors")
                 .required(false)
+                .global(true)
                 .action(ArgAction::SetTrue),
         )
+        .arg(
+            arg!(--"log-format" <FORMAT> "Print logs and progress as text or JSON lines")
+                .required(false)
+                .global(true)
+                .default_value("text")
+                .value_parser(["text", "json"]),
+        )
         .get_matches();
 
+    let log_format = match matches.get_one::<String>("log-format").map(String::as_str) {
+        Some("json") => LogFormat::Json,
+        _ => LogFormat::Text,
+    };
+    log::init(log_format, matches.get_flag("quiet"));
+    info!("Starting...");
+
+    if let Some(catalog_file) = matches.get_one::<PathBuf>("catalog") {
+        // installed first, as the layers list its colour variants
+        catalog::init(catalog::Catalog::load(catalog_file)?);
+        let catalog = catalog::get();
+        validate_catalog(catalog_file, catalog)?;
+        info!(
+            "Load {} boy and {} girl outfit sets, {} placements, {} blends, {} colour variants, {} procedural backgrounds and {} links from {catalog_file:?}",
+            catalog.sets(Gender::Boy).len(),
+            catalog.sets(Gender::Girl).len(),
+            catalog.placements().count(),
+            catalog.blends().count(),
+            catalog.colour_variants().count(),
+            catalog.procedural_backgrounds().count(),
+            catalog.links().count()
+        );
+    }
+    validate_links()?;
+
+    shutdown::listen();
+    let is_resume = matches.get_flag("resume");
+
+    let jobs = matches
+        .get_one::<usize>("jobs")
+        .copied()
+        .unwrap_or_else(render::default_jobs);
+    if jobs == 0 {
+        return Err(Error::Validation("--jobs must be at least 1".to_string()));
+    }
+    info!("Render with {jobs} parallel jobs");
+
     match matches.subcommand() {
         Some(("generate-emotions", args)) => {
             if let Some(mapping_file) = args.get_one::<PathBuf>("from") {
@@ -66,33 +387,361 @@ async fn main() {
                     .expect("Missing input dir");
 
                 let mut output_dir = PathBuf::from("emotions_girl");
-                if is_boy(&input_dir) {
+                if is_boy(input_dir) {
                     output_dir = PathBuf::from("emotions_boy");
                 }
 
                 let from_index = args.get_one::<usize>("from-index").unwrap_or(&0);
 
-                generate_emotions(mapping_file, input_dir, &output_dir, *from_index).await;
-                return;
+                return generate_emotions(
+                    mapping_file,
+                    input_dir,
+                    &output_dir,
+                    *from_index,
+                    is_resume,
+                    jobs,
+                )
+                .await;
+            }
+        }
+        Some(("export-stickers", args)) => {
+            let mapping_file = args
+                .get_one::<PathBuf>("from")
+                .expect("Missing mapping file");
+            info!("Export stickers from mapping file {mapping_file:?}");
+
+            let input_dir = args
+                .get_one::<PathBuf>("input-dir")
+                .expect("Missing input dir");
+
+            let output_dir = match args.get_one::<PathBuf>("output-dir") {
+                Some(output_dir) => output_dir.clone(),
+                None if is_boy(input_dir) => PathBuf::from("stickers_boy"),
+                None => PathBuf::from("stickers_girl"),
+            };
+            info!("Output directory {:?}", output_dir);
+
+            let from_index = args.get_one::<usize>("from-index").unwrap_or(&0);
+            let name = args.get_one::<String>("name").expect("Missing pack name");
+            let max_bytes = args.get_one::<u64>("max-bytes").expect("Missing max bytes");
+            let emoji_max_bytes = args
+                .get_one::<u64>("emoji-max-bytes")
+                .expect("Missing emoji max bytes");
+
+            // stickers are cut out of a transparent background, so the
+            // Background layer is left out of every stack
+            let stacks = emotion_stacks(mapping_file, *from_index)?
+                .into_iter()
+                .map(|(index, emotions)| {
+                    let emotions = emotions
+                        .into_iter()
+                        .map(|mut parts| {
+                            parts.retain(|part| {
+                                !matches!(layer_of_part(part), Some(Parts::Background(_)))
+                            });
+                            parts
+                        })
+                        .collect();
+                    (index, emotions)
+                })
+                .collect();
+            return stickers::export_stickers(
+                stacks,
+                input_dir,
+                &output_dir,
+                name,
+                *max_bytes,
+                *emoji_max_bytes,
+                jobs,
+            )
+            .await;
+        }
+        Some(("pack-emotions", args)) => {
+            let emotions_dir = args
+                .get_one::<PathBuf>("emotions-dir")
+                .expect("Missing emotions dir");
+            info!("Pack emotions from directory {emotions_dir:?}");
+
+            let output_dir = match args.get_one::<PathBuf>("output-dir") {
+                Some(output_dir) => output_dir.clone(),
+                None => {
+                    let mut name = emotions_dir.as_os_str().to_owned();
+                    name.push("_sheets");
+                    PathBuf::from(name)
+                }
+            };
+            info!("Output directory {:?}", output_dir);
+
+            let columns = args.get_one::<usize>("columns").unwrap_or(&5);
+            if *columns == 0 {
+                return Err(Error::Validation(
+                    "--columns must be at least 1".to_string(),
+                ));
+            }
+
+            return atlas::pack_emotions(emotions_dir, &output_dir, *columns, jobs).await;
+        }
+        Some(("frame", args)) => {
+            let mapping_file = args
+                .get_one::<PathBuf>("from")
+                .expect("Missing mapping file");
+            info!("Frame metronions from mapping file {mapping_file:?}");
+
+            let output_dir = args
+                .get_one::<PathBuf>("output-dir")
+                .expect("Missing output dir");
+            let min_rarity = args
+                .get_one::<String>("min-rarity")
+                .and_then(|name| Rarity::ALL.into_iter().find(|rarity| rarity.name() == name))
+                .unwrap_or(Rarity::Common);
+
+            let catalogs = [Gender::Boy, Gender::Girl].map(|gender| (gender, rarities(gender)));
+            let mut tokens = vec![];
+            let mut failures = vec![];
+            let mut images = 0;
+            for (id, parts) in mapping::read(mapping_file)? {
+                // a pinned image has no parts to rate, and would match
+                // every catalog
+                if parts.is_empty() {
+                    images += 1;
+                    continue;
+                }
+                // the gender whose catalog has every part
+                let Some((gender, rarity_of)) = catalogs.iter().find(|(_, rarity_of)| {
+                    parts
+                        .iter()
+                        .all(|part| rarity_of.contains_key(part.as_str()))
+                }) else {
+                    return Err(Error::Validation(format!(
+                        "{mapping_file:?}: metronion {id} has parts of neither catalog"
+                    )));
+                };
+                // a metronion whose set cannot be told is left unframed
+                let set = match recorded_set(output_dir, *gender, id) {
+                    Ok(set) => set,
+                    Err(err) => {
+                        error!("Metronion {id} is not framed: {err}");
+                        failures.push(RenderFailure {
+                            token_id: id,
+                            parts,
+                            attempts: 0,
+                            stderr: err.to_string(),
+                        });
+                        continue;
+                    }
+                };
+                let rarity = token_rarity(rarity_of, &parts, set);
+                if id > 0 && rarity >= min_rarity {
+                    tokens.push((id - 1, parts, rarity.frame()));
+                }
+            }
+            if images > 0 {
+                info!("Leave {images} pinned images unframed");
+            }
+
+            return frames::frame_metronions(tokens, failures, output_dir, jobs).await;
+        }
+        Some(("stats", args)) => {
+            let genders = match args.get_one::<String>("gender").map(String::as_str) {
+                Some("boy") => vec![Gender::Boy],
+                Some("girl") => vec![Gender::Girl],
+                _ => vec![Gender::Boy, Gender::Girl],
+            };
+
+            let mut all_stats = BTreeMap::new();
+            for gender in genders {
+                let count = args
+                    .get_one::<usize>("count")
+                    .copied()
+                    .unwrap_or(match gender {
+                        Gender::Boy => TOTAL_BOYS,
+                        Gender::Girl => TOTAL_GIRLS,
+                    });
+                let stats = stats_model(gender).stats()?;
+                log_stats(gender, &stats, count);
+                all_stats.insert(gender.to_string(), stats);
+            }
+
+            if let Some(output) = args.get_one::<PathBuf>("output") {
+                files::write_json_atomic(output, &all_stats)?;
+                info!("Write statistics to {output:?}");
+            }
+            return Ok(());
+        }
+        Some(("simulate", args)) => {
+            let genders = match args.get_one::<String>("gender").map(String::as_str) {
+                Some("boy") => vec![Gender::Boy],
+                Some("girl") => vec![Gender::Girl],
+                _ => vec![Gender::Boy, Gender::Girl],
+            };
+            let runs = *args.get_one::<usize>("runs").unwrap_or(&1000);
+            if runs == 0 {
+                return Err(Error::Validation("--runs must be at least 1".to_string()));
+            }
+
+            let mut simulations = BTreeMap::new();
+            for gender in genders {
+                let count = args
+                    .get_one::<usize>("count")
+                    .copied()
+                    .unwrap_or(match gender {
+                        Gender::Boy => TOTAL_BOYS,
+                        Gender::Girl => TOTAL_GIRLS,
+                    });
+                info!("Simulate {runs} collections of {count} {gender} metronions");
+
+                let variants = get_parts_order()
+                    .iter()
+                    .flat_map(|part| {
+                        Rarity::ALL.into_iter().zip(part.tiers(gender)).flat_map(
+                            move |(rarity, tier)| {
+                                tier.iter().map(move |&variant| simulate::Variant {
+                                    layer: part.layer_name(),
+                                    variant,
+                                    rarity: rarity.name(),
+                                })
+                            },
+                        )
+                    })
+                    .collect::<Vec<simulate::Variant>>();
+                let simulation = simulate::simulate(
+                    &variants,
+                    runs,
+                    count,
+                    jobs,
+                    Rarity::Mythical.name(),
+                    || {
+                        Ok(generate_random_metronion(gender)?
+                            .1
+                            .into_iter()
+                            .map(|(_, part)| part)
+                            .collect())
+                    },
+                )?;
+                log_simulation(gender, &simulation);
+                simulations.insert(gender.to_string(), simulation);
+            }
+
+            if let Some(output) = args.get_one::<PathBuf>("output") {
+                files::write_json_atomic(output, &simulations)?;
+                info!("Write simulation to {output:?}");
+            }
+            return Ok(());
+        }
+        Some(("extend", args)) => {
+            let mapping_file = args
+                .get_one::<PathBuf>("from")
+                .expect("Missing mapping file");
+            info!("Extend mapping file {mapping_file:?}");
+
+            let input_dir = args
+                .get_one::<PathBuf>("input-dir")
+                .expect("Missing input dir");
+            let output_dir = args.get_one::<PathBuf>("output-dir").unwrap();
+            info!("Output directory {:?}", output_dir);
+
+            if is_resume {
+                return resume(output_dir, jobs).await;
+            }
+
+            let generator = Generator::new(input_dir, output_dir, args, jobs)?;
+            let total = match generator.gender {
+                Gender::Boy => TOTAL_BOYS,
+                Gender::Girl => TOTAL_GIRLS,
+            };
+            let count = args.get_one::<usize>("count").expect("Missing count");
+            let max_supply = args.get_one::<usize>("max-supply").unwrap_or(&total);
+
+            return extend(&generator, mapping_file, *count, *max_supply).await;
+        }
+        Some(("reroll", args)) => {
+            let mapping_file = args
+                .get_one::<PathBuf>("from")
+                .expect("Missing mapping file");
+            info!("Reroll metronions of mapping file {mapping_file:?}");
+
+            let input_dir = args
+                .get_one::<PathBuf>("input-dir")
+                .expect("Missing input dir");
+            let output_dir = args.get_one::<PathBuf>("output-dir").unwrap();
+            info!("Output directory {:?}", output_dir);
+
+            if is_resume {
+                return resume(output_dir, jobs).await;
+            }
+
+            let mut ids = args
+                .get_many::<usize>("ids")
+                .map(|ids| ids.copied().collect::<Vec<usize>>())
+                .unwrap_or_default();
+            if let Some(ids_file) = args.get_one::<PathBuf>("ids-file") {
+                ids.extend(mapping::read_ids(ids_file)?);
             }
+            if ids.is_empty() {
+                return Err(Error::Validation(
+                    "nothing to reroll, pass --ids or --ids-file".to_string(),
+                ));
+            }
+
+            let generator = Generator::new(input_dir, output_dir, args, jobs)?;
+
+            return reroll(&generator, mapping_file, ids).await;
         }
         _ => {}
     }
 
+    if is_resume {
+        let output_dir = matches.get_one::<PathBuf>("output-dir").unwrap();
+        info!("Resume in output directory {:?}", output_dir);
+
+        return resume(output_dir, jobs).await;
+    }
+
     if let Some(dir) = matches.get_one::<PathBuf>("input-dir") {
         info!("Working on directory {dir:?}");
 
         let output_dir = matches.get_one::<PathBuf>("output-dir").unwrap();
         info!("Output directory {:?}", output_dir);
 
-        let is_reset = matches.get_one::<bool>("reset").unwrap_or(&true);
-
-        if is_boy(&dir) {
-            handle(&dir, &output_dir, Gender::Boy, TOTAL_BOYS, *is_reset).await;
+        let mode = if matches.get_flag("overwrite") {
+            MappingMode::Overwrite
+        } else if matches.get_flag("append-continue-ids") {
+            MappingMode::AppendContinueIds
         } else {
-            handle(&dir, &output_dir, Gender::Girl, TOTAL_GIRLS, *is_reset).await;
+            MappingMode::Refuse
+        };
+
+        let generator = Generator::new(dir, output_dir, &matches, jobs)?;
+        match generator.gender {
+            Gender::Boy => handle(&generator, TOTAL_BOYS, mode).await?,
+            Gender::Girl => handle(&generator, TOTAL_GIRLS, mode).await?,
         }
     }
+
+    Ok(())
+}
+
+// Options of the commands adding metronions to a collection.
+fn generator_args() -> [Arg; 6] {
+    [
+        arg!(--pinned <FILE> "JSON file of metronions pinned at specific ids")
+            .required(false)
+            .value_parser(value_parser!(PathBuf)),
+        arg!(--rules <FILE> "JSON file of constraints such as banned combinations")
+            .required(false)
+            .value_parser(value_parser!(PathBuf)),
+        arg!(--"block-size" <IDS> "Spread each rarity tier evenly over blocks of this many ids")
+            .required(false)
+            .value_parser(value_parser!(usize)),
+        arg!(--"exact-quotas" "Hand out every variant exactly as often as its probability")
+            .action(ArgAction::SetTrue),
+        arg!(--quotas <FILE> "JSON file of exact variant counts, implies --exact-quotas")
+            .required(false)
+            .value_parser(value_parser!(PathBuf)),
+        arg!(--derivatives <FILE> "JSON file of resized copies made of every rendered metronion")
+            .required(false)
+            .value_parser(value_parser!(PathBuf)),
+    ]
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -114,41 +763,53 @@ impl Display for Gender {
     }
 }
 
+// Variants of a layer for each rarity, from Common to Mythical.
+type Tiers = [&'static [&'static str]; 6];
+
+// The variants of each rarity, with those the catalog file adds.
+type CatalogTiers = [Vec<&'static str>; 6];
+
 trait RandomizedPart {
-    fn random_part(gender: Gender) -> Option<&'static str>;
+    fn tiers(gender: Gender) -> Tiers;
 }
 
 struct Background {}
 impl RandomizedPart for Background {
-    fn random_part(_gender: Gender) -> Option<&'static str> {
-        let rarity = Rarity::pick_random_rarity();
-
-        match rarity {
-            Rarity::Common => Some(pick_random(&[
-                "NFT_BG_1", "NFT_BG_2", "NFT_BG_3", "NFT_BG_4",
-            ])),
-            Rarity::Uncommon => None,
-            Rarity::Rare => Some(pick_random(&["NFT_BG_5", "NFT_BG_6"])),
-            Rarity::Epic => Some(pick_random(&["NFT_BG_7", "NFT_BG_9", "NFT_BG_10"])),
-            Rarity::Legendary => Some(pick_random(&["NFT_BG_12"])),
-            Rarity::Mythical => Some(pick_random(&["NFT_BG_8", "NFT_BG_11"])),
-        }
+    fn tiers(_gender: Gender) -> Tiers {
+        [
+            // Common
+            &["NFT_BG_1", "NFT_BG_2", "NFT_BG_3", "NFT_BG_4"],
+            // Uncommon
+            &[],
+            // Rare
+            &["NFT_BG_5", "NFT_BG_6"],
+            // Epic
+            &["NFT_BG_7", "NFT_BG_9", "NFT_BG_10"],
+            // Legendary
+            &["NFT_BG_12"],
+            // Mythical
+            &["NFT_BG_8", "NFT_BG_11"],
+        ]
     }
 }
 
 struct Hand {}
 impl RandomizedPart for Hand {
-    fn random_part(_gender: Gender) -> Option<&'static str> {
-        let rarity = Rarity::pick_random_rarity();
-
-        match rarity {
-            Rarity::Common => Some(pick_random(&["NFT_Hand_1", "NFT_Hand_2"])),
-            Rarity::Uncommon => Some(pick_random(&["NFT_Hand_3", "NFT_Hand_4"])),
-            Rarity::Rare => Some(pick_random(&["NFT_Hand_5", "NFT_Hand_6"])),
-            Rarity::Epic => Some(pick_random(&["NFT_Hand_7", "NFT_Hand_8"])),
-            Rarity::Legendary => Some(pick_random(&["NFT_Hand_9", "NFT_Hand_10", "NFT_Hand_11"])),
-            Rarity::Mythical => Some(pick_random(&["NFT_Hand_12", "NFT_Hand_13"])),
-        }
+    fn tiers(_gender: Gender) -> Tiers {
+        [
+            // Common
+            &["NFT_Hand_1", "NFT_Hand_2"],
+            // Uncommon
+            &["NFT_Hand_3", "NFT_Hand_4"],
+            // Rare
+            &["NFT_Hand_5", "NFT_Hand_6"],
+            // Epic
+            &["NFT_Hand_7", "NFT_Hand_8"],
+            // Legendary
+            &["NFT_Hand_9", "NFT_Hand_10", "NFT_Hand_11"],
+            // Mythical
+            &["NFT_Hand_12", "NFT_Hand_13"],
+        ]
     }
 }
 
@@ -156,250 +817,268 @@ struct HairLong {}
 
 impl HairLong {
     fn is_with_headphone(gender: Gender, variant: &str) -> bool {
+        let variant = HairLong::base(variant);
         match gender {
-            Gender::Boy => match variant {
-                "NFT_B_Hair_Long_2" | "NFT_B_Hair_Long_3" | "NFT_B_Hair_Long_4"
-                | "NFT_B_Hair_Long_7" | "NFT_B_Hair_Long_8" | "NFT_B_Hair_Long_9"
-                | "NFT_B_Hair_Long_10" | "NFT_B_Hair_Long_1" | "NFT_B_Hair_Long_5"
-                | "NFT_B_Hair_Long_6" => false,
-                _ => true,
-            },
-            Gender::Girl => match variant {
-                "NFT_G_Hair_Long_4" | "NFT_G_Hair_Long_5" | "NFT_G_Hair_Long_6"
-                | "NFT_G_Hair_Long_7" | "NFT_G_Hair_Long_8" | "NFT_G_Hair_Long_9"
-                | "NFT_G_Hair_Long_10" | "NFT_G_Hair_Long_13" | "NFT_G_Hair_Long_14"
-                | "NFT_G_Hair_Long_15" | "NFT_G_Hair_Long_16" | "NFT_G_Hair_Long_1"
-                | "NFT_G_Hair_Long_2" | "NFT_G_Hair_Long_3" | "NFT_G_Hair_Long_11"
-                | "NFT_G_Hair_Long_12" => false,
-                _ => true,
-            },
+            Gender::Boy => !matches!(
+                variant,
+                "NFT_B_Hair_Long_2"
+                    | "NFT_B_Hair_Long_3"
+                    | "NFT_B_Hair_Long_4"
+                    | "NFT_B_Hair_Long_7"
+                    | "NFT_B_Hair_Long_8"
+                    | "NFT_B_Hair_Long_9"
+                    | "NFT_B_Hair_Long_10"
+                    | "NFT_B_Hair_Long_1"
+                    | "NFT_B_Hair_Long_5"
+                    | "NFT_B_Hair_Long_6"
+            ),
+            Gender::Girl => !matches!(
+                variant,
+                "NFT_G_Hair_Long_4"
+                    | "NFT_G_Hair_Long_5"
+                    | "NFT_G_Hair_Long_6"
+                    | "NFT_G_Hair_Long_7"
+                    | "NFT_G_Hair_Long_8"
+                    | "NFT_G_Hair_Long_9"
+                    | "NFT_G_Hair_Long_10"
+                    | "NFT_G_Hair_Long_13"
+                    | "NFT_G_Hair_Long_14"
+                    | "NFT_G_Hair_Long_15"
+                    | "NFT_G_Hair_Long_16"
+                    | "NFT_G_Hair_Long_1"
+                    | "NFT_G_Hair_Long_2"
+                    | "NFT_G_Hair_Long_3"
+                    | "NFT_G_Hair_Long_11"
+                    | "NFT_G_Hair_Long_12"
+            ),
         }
     }
     fn is_with_face_acc(variant: &str) -> bool {
-        match variant {
-            "NFT_B_Hair_Long_32" | "NFT_B_Hair_Long_33" | "NFT_B_Hair_Long_34" => false,
-            _ => true,
-        }
+        let variant = HairLong::base(variant);
+        !matches!(
+            variant,
+            "NFT_B_Hair_Long_32" | "NFT_B_Hair_Long_33" | "NFT_B_Hair_Long_34"
+        )
+    }
+
+    // A colour variant fits like the variant it is recoloured from.
+    fn base(variant: &str) -> &str {
+        catalog::get()
+            .colour_variant(variant)
+            .map_or(variant, |colour| colour.base.as_str())
+    }
+
+    // Only boys have long hair hiding the face accessory.
+    fn allows_face_acc(gender: Gender, variant: &str) -> bool {
+        matches!(gender, Gender::Girl) || HairLong::is_with_face_acc(variant)
     }
 }
 impl RandomizedPart for HairLong {
-    fn random_part(gender: Gender) -> Option<&'static str> {
+    fn tiers(gender: Gender) -> Tiers {
         match gender {
-            Gender::Boy => {
-                let rarity = Rarity::pick_random_rarity();
-
-                match rarity {
-                    Rarity::Common => Some(pick_random(&[
-                        "NFT_B_Hair_Long_11",
-                        "NFT_B_Hair_Long_12",
-                        "NFT_B_Hair_Long_13",
-                        "NFT_B_Hair_Long_14",
-                        "NFT_B_Hair_Long_15",
-                        "NFT_B_Hair_Long_16",
-                        "NFT_B_Hair_Long_17",
-                        "NFT_B_Hair_Long_18",
-                        "NFT_B_Hair_Long_19",
-                        "NFT_B_Hair_Long_20",
-                        "NFT_B_Hair_Long_21",
-                        "NFT_B_Hair_Long_22",
-                        "NFT_B_Hair_Long_23",
-                        "NFT_B_Hair_Long_24",
-                        "NFT_B_Hair_Long_25",
-                        "NFT_B_Hair_Long_26",
-                        "NFT_B_Hair_Long_27",
-                        "NFT_B_Hair_Long_28",
-                        "NFT_B_Hair_Long_29",
-                        "NFT_B_Hair_Long_30",
-                        "NFT_B_Hair_Long_31",
-                    ])),
-                    Rarity::Uncommon => Some(pick_random(&[
-                        "NFT_B_Hair_Long_2",
-                        "NFT_B_Hair_Long_3",
-                        "NFT_B_Hair_Long_4",
-                        "NFT_B_Hair_Long_7",
-                        "NFT_B_Hair_Long_8",
-                        "NFT_B_Hair_Long_9",
-                        "NFT_B_Hair_Long_10",
-                    ])),
-                    Rarity::Rare => Some(pick_random(&[
-                        "NFT_B_Hair_Long_32",
-                        "NFT_B_Hair_Long_33",
-                        "NFT_B_Hair_Long_34",
-                        "NFT_B_Hair_Long_35",
-                        "NFT_B_Hair_Long_36",
-                        "NFT_B_Hair_Long_37",
-                        "NFT_B_Hair_Long_38",
-                    ])),
-                    Rarity::Epic => Some(pick_random(&[
-                        "NFT_B_Hair_Long_1",
-                        "NFT_B_Hair_Long_5",
-                        "NFT_B_Hair_Long_6",
-                    ])),
-                    Rarity::Legendary => {
-                        Some(pick_random(&["NFT_B_Hair_Long_39", "NFT_B_Hair_Long_41"]))
-                    }
-                    Rarity::Mythical => {
-                        Some(pick_random(&["NFT_B_Hair_Long_40", "NFT_B_Hair_Long_42"]))
-                    }
-                }
-            }
-            Gender::Girl => {
-                let rarity = Rarity::pick_random_rarity();
-
-                match rarity {
-                    Rarity::Common => Some(pick_random(&[
-                        "NFT_G_Hair_Long_17",
-                        "NFT_G_Hair_Long_18",
-                        "NFT_G_Hair_Long_19",
-                        "NFT_G_Hair_Long_20",
-                        "NFT_G_Hair_Long_21",
-                        "NFT_G_Hair_Long_22",
-                        "NFT_G_Hair_Long_23",
-                        "NFT_G_Hair_Long_24",
-                        "NFT_G_Hair_Long_25",
-                        "NFT_G_Hair_Long_26",
-                        "NFT_G_Hair_Long_27",
-                        "NFT_G_Hair_Long_28",
-                        "NFT_G_Hair_Long_29",
-                        "NFT_G_Hair_Long_30",
-                        "NFT_G_Hair_Long_31",
-                    ])),
-                    Rarity::Uncommon => Some(pick_random(&[
-                        "NFT_G_Hair_Long_4",
-                        "NFT_G_Hair_Long_5",
-                        "NFT_G_Hair_Long_6",
-                        "NFT_G_Hair_Long_7",
-                        "NFT_G_Hair_Long_8",
-                        "NFT_G_Hair_Long_9",
-                        "NFT_G_Hair_Long_10",
-                        "NFT_G_Hair_Long_13",
-                        "NFT_G_Hair_Long_14",
-                        "NFT_G_Hair_Long_15",
-                        "NFT_G_Hair_Long_16",
-                        "NFT_G_Hair_Long_32",
-                        "NFT_G_Hair_Long_33",
-                        "NFT_G_Hair_Long_37",
-                        "NFT_G_Hair_Long_38",
-                        "NFT_G_Hair_Long_39",
-                        "NFT_G_Hair_Long_40",
-                        "NFT_G_Hair_Long_41",
-                        "NFT_G_Hair_Long_42",
-                    ])),
-                    Rarity::Rare => Some(pick_random(&[
-                        "NFT_G_Hair_Long_34",
-                        "NFT_G_Hair_Long_35",
-                        "NFT_G_Hair_Long_36",
-                    ])),
-                    Rarity::Epic => Some(pick_random(&[
-                        "NFT_G_Hair_Long_1",
-                        "NFT_G_Hair_Long_2",
-                        "NFT_G_Hair_Long_3",
-                        "NFT_G_Hair_Long_11",
-                        "NFT_G_Hair_Long_12",
-                    ])),
-                    Rarity::Legendary => {
-                        Some(pick_random(&["NFT_G_Hair_Long_43", "NFT_G_Hair_Long_45"]))
-                    }
-                    Rarity::Mythical => {
-                        Some(pick_random(&["NFT_G_Hair_Long_44", "NFT_G_Hair_Long_46"]))
-                    }
-                }
-            }
+            Gender::Boy => [
+                // Common
+                &[
+                    "NFT_B_Hair_Long_11",
+                    "NFT_B_Hair_Long_12",
+                    "NFT_B_Hair_Long_13",
+                    "NFT_B_Hair_Long_14",
+                    "NFT_B_Hair_Long_15",
+                    "NFT_B_Hair_Long_16",
+                    "NFT_B_Hair_Long_17",
+                    "NFT_B_Hair_Long_18",
+                    "NFT_B_Hair_Long_19",
+                    "NFT_B_Hair_Long_20",
+                    "NFT_B_Hair_Long_21",
+                    "NFT_B_Hair_Long_22",
+                    "NFT_B_Hair_Long_23",
+                    "NFT_B_Hair_Long_24",
+                    "NFT_B_Hair_Long_25",
+                    "NFT_B_Hair_Long_26",
+                    "NFT_B_Hair_Long_27",
+                    "NFT_B_Hair_Long_28",
+                    "NFT_B_Hair_Long_29",
+                    "NFT_B_Hair_Long_30",
+                    "NFT_B_Hair_Long_31",
+                ],
+                // Uncommon
+                &[
+                    "NFT_B_Hair_Long_2",
+                    "NFT_B_Hair_Long_3",
+                    "NFT_B_Hair_Long_4",
+                    "NFT_B_Hair_Long_7",
+                    "NFT_B_Hair_Long_8",
+                    "NFT_B_Hair_Long_9",
+                    "NFT_B_Hair_Long_10",
+                ],
+                // Rare
+                &[
+                    "NFT_B_Hair_Long_32",
+                    "NFT_B_Hair_Long_33",
+                    "NFT_B_Hair_Long_34",
+                    "NFT_B_Hair_Long_35",
+                    "NFT_B_Hair_Long_36",
+                    "NFT_B_Hair_Long_37",
+                    "NFT_B_Hair_Long_38",
+                ],
+                // Epic
+                &[
+                    "NFT_B_Hair_Long_1",
+                    "NFT_B_Hair_Long_5",
+                    "NFT_B_Hair_Long_6",
+                ],
+                // Legendary
+                &["NFT_B_Hair_Long_39", "NFT_B_Hair_Long_41"],
+                // Mythical
+                &["NFT_B_Hair_Long_40", "NFT_B_Hair_Long_42"],
+            ],
+            Gender::Girl => [
+                // Common
+                &[
+                    "NFT_G_Hair_Long_17",
+                    "NFT_G_Hair_Long_18",
+                    "NFT_G_Hair_Long_19",
+                    "NFT_G_Hair_Long_20",
+                    "NFT_G_Hair_Long_21",
+                    "NFT_G_Hair_Long_22",
+                    "NFT_G_Hair_Long_23",
+                    "NFT_G_Hair_Long_24",
+                    "NFT_G_Hair_Long_25",
+                    "NFT_G_Hair_Long_26",
+                    "NFT_G_Hair_Long_27",
+                    "NFT_G_Hair_Long_28",
+                    "NFT_G_Hair_Long_29",
+                    "NFT_G_Hair_Long_30",
+                    "NFT_G_Hair_Long_31",
+                ],
+                // Uncommon
+                &[
+                    "NFT_G_Hair_Long_4",
+                    "NFT_G_Hair_Long_5",
+                    "NFT_G_Hair_Long_6",
+                    "NFT_G_Hair_Long_7",
+                    "NFT_G_Hair_Long_8",
+                    "NFT_G_Hair_Long_9",
+                    "NFT_G_Hair_Long_10",
+                    "NFT_G_Hair_Long_13",
+                    "NFT_G_Hair_Long_14",
+                    "NFT_G_Hair_Long_15",
+                    "NFT_G_Hair_Long_16",
+                    "NFT_G_Hair_Long_32",
+                    "NFT_G_Hair_Long_33",
+                    "NFT_G_Hair_Long_37",
+                    "NFT_G_Hair_Long_38",
+                    "NFT_G_Hair_Long_39",
+                    "NFT_G_Hair_Long_40",
+                    "NFT_G_Hair_Long_41",
+                    "NFT_G_Hair_Long_42",
+                ],
+                // Rare
+                &[
+                    "NFT_G_Hair_Long_34",
+                    "NFT_G_Hair_Long_35",
+                    "NFT_G_Hair_Long_36",
+                ],
+                // Epic
+                &[
+                    "NFT_G_Hair_Long_1",
+                    "NFT_G_Hair_Long_2",
+                    "NFT_G_Hair_Long_3",
+                    "NFT_G_Hair_Long_11",
+                    "NFT_G_Hair_Long_12",
+                ],
+                // Legendary
+                &["NFT_G_Hair_Long_43", "NFT_G_Hair_Long_45"],
+                // Mythical
+                &["NFT_G_Hair_Long_44", "NFT_G_Hair_Long_46"],
+            ],
         }
     }
 }
 
 struct Body {}
 impl RandomizedPart for Body {
-    fn random_part(_gender: Gender) -> Option<&'static str> {
-        let rarity = Rarity::pick_random_rarity();
-
-        match rarity {
-            Rarity::Common => Some(pick_random(&["NFT_Body_1", "NFT_Body_2", "NFT_Body_3"])),
-            Rarity::Uncommon => None,
-            Rarity::Rare => None,
-            Rarity::Epic => Some(pick_random(&["NFT_Body_4"])),
-            Rarity::Legendary => None,
-            Rarity::Mythical => None,
-        }
+    fn tiers(_gender: Gender) -> Tiers {
+        [
+            // Common
+            &["NFT_Body_1", "NFT_Body_2", "NFT_Body_3"],
+            // Uncommon
+            &[],
+            // Rare
+            &[],
+            // Epic
+            &["NFT_Body_4"],
+            // Legendary
+            &[],
+            // Mythical
+            &[],
+        ]
     }
 }
 
 struct Clothes {}
 impl RandomizedPart for Clothes {
-    fn random_part(gender: Gender) -> Option<&'static str> {
+    fn tiers(gender: Gender) -> Tiers {
         match gender {
-            Gender::Boy => {
-                let rarity = Rarity::pick_random_rarity();
-
-                match rarity {
-                    Rarity::Common => Some(pick_random(&[
-                        "NFT_B_Clothes_1",
-                        "NFT_B_Clothes_2",
-                        "NFT_B_Clothes_3",
-                        "NFT_B_Clothes_4",
-                        "NFT_B_Clothes_5",
-                        "NFT_B_Clothes_6",
-                    ])),
-                    Rarity::Uncommon => Some(pick_random(&[
-                        "NFT_B_Clothes_7",
-                        "NFT_B_Clothes_8",
-                        "NFT_B_Clothes_9",
-                    ])),
-                    Rarity::Rare => Some(pick_random(&[
-                        "NFT_B_Clothes_10",
-                        "NFT_B_Clothes_12",
-                        "NFT_B_Clothes_17",
-                    ])),
-                    Rarity::Epic => Some(pick_random(&[
-                        "NFT_B_Clothes_11",
-                        "NFT_B_Clothes_13",
-                        "NFT_B_Clothes_14",
-                        "NFT_B_Clothes_15",
-                        "NFT_B_Clothes_16",
-                    ])),
-                    Rarity::Legendary => {
-                        Some(pick_random(&["NFT_B_Clothes_18", "NFT_B_Clothes_19"]))
-                    }
-                    Rarity::Mythical => {
-                        Some(pick_random(&["NFT_B_Clothes_20", "NFT_B_Clothes_21"]))
-                    }
-                }
-            }
-            Gender::Girl => {
-                let rarity = Rarity::pick_random_rarity();
-
-                match rarity {
-                    Rarity::Common => Some(pick_random(&[
-                        "NFT_G_Clothes_1",
-                        "NFT_G_Clothes_2",
-                        "NFT_G_Clothes_3",
-                        "NFT_G_Clothes_4",
-                        "NFT_G_Clothes_5",
-                        "NFT_G_Clothes_6",
-                    ])),
-                    Rarity::Uncommon => Some(pick_random(&[
-                        "NFT_G_Clothes_7",
-                        "NFT_G_Clothes_8",
-                        "NFT_G_Clothes_12",
-                        "NFT_G_Clothes_13",
-                        "NFT_G_Clothes_14",
-                    ])),
-                    Rarity::Rare => Some(pick_random(&["NFT_G_Clothes_9", "NFT_G_Clothes_10"])),
-                    Rarity::Epic => Some(pick_random(&[
-                        "NFT_G_Clothes_11",
-                        "NFT_G_Clothes_15",
-                        "NFT_G_Clothes_16",
-                        "NFT_G_Clothes_17",
-                    ])),
-                    Rarity::Legendary => {
-                        Some(pick_random(&["NFT_G_Clothes_18", "NFT_G_Clothes_19"]))
-                    }
-                    Rarity::Mythical => {
-                        Some(pick_random(&["NFT_G_Clothes_20", "NFT_G_Clothes_21"]))
-                    }
-                }
-            }
+            Gender::Boy => [
+                // Common
+                &[
+                    "NFT_B_Clothes_1",
+                    "NFT_B_Clothes_2",
+                    "NFT_B_Clothes_3",
+                    "NFT_B_Clothes_4",
+                    "NFT_B_Clothes_5",
+                    "NFT_B_Clothes_6",
+                ],
+                // Uncommon
+                &["NFT_B_Clothes_7", "NFT_B_Clothes_8", "NFT_B_Clothes_9"],
+                // Rare
+                &["NFT_B_Clothes_10", "NFT_B_Clothes_12", "NFT_B_Clothes_17"],
+                // Epic
+                &[
+                    "NFT_B_Clothes_11",
+                    "NFT_B_Clothes_13",
+                    "NFT_B_Clothes_14",
+                    "NFT_B_Clothes_15",
+                    "NFT_B_Clothes_16",
+                ],
+                // Legendary
+                &["NFT_B_Clothes_18", "NFT_B_Clothes_19"],
+                // Mythical
+                &["NFT_B_Clothes_20", "NFT_B_Clothes_21"],
+            ],
+            Gender::Girl => [
+                // Common
+                &[
+                    "NFT_G_Clothes_1",
+                    "NFT_G_Clothes_2",
+                    "NFT_G_Clothes_3",
+                    "NFT_G_Clothes_4",
+                    "NFT_G_Clothes_5",
+                    "NFT_G_Clothes_6",
+                ],
+                // Uncommon
+                &[
+                    "NFT_G_Clothes_7",
+                    "NFT_G_Clothes_8",
+                    "NFT_G_Clothes_12",
+                    "NFT_G_Clothes_13",
+                    "NFT_G_Clothes_14",
+                ],
+                // Rare
+                &["NFT_G_Clothes_9", "NFT_G_Clothes_10"],
+                // Epic
+                &[
+                    "NFT_G_Clothes_11",
+                    "NFT_G_Clothes_15",
+                    "NFT_G_Clothes_16",
+                    "NFT_G_Clothes_17",
+                ],
+                // Legendary
+                &["NFT_G_Clothes_18", "NFT_G_Clothes_19"],
+                // Mythical
+                &["NFT_G_Clothes_20", "NFT_G_Clothes_21"],
+            ],
         }
     }
 }
@@ -407,89 +1086,89 @@ impl RandomizedPart for Clothes {
 struct Face {}
 
 impl RandomizedPart for Face {
-    fn random_part(gender: Gender) -> Option<&'static str> {
+    fn tiers(gender: Gender) -> Tiers {
         match gender {
-            Gender::Boy => {
-                let rarity = Rarity::pick_random_rarity();
-
-                match rarity {
-                    Rarity::Common => Some(pick_random(&[
-                        "NFT_B_Face_1",
-                        "NFT_B_Face_2",
-                        "NFT_B_Face_3",
-                        "NFT_B_Face_4",
-                        "NFT_B_Face_13",
-                        "NFT_B_Face_14",
-                    ])),
-                    Rarity::Uncommon => Some(pick_random(&[
-                        "NFT_B_Face_5",
-                        "NFT_B_Face_6",
-                        "NFT_B_Face_7",
-                        "NFT_B_Face_8",
-                        "NFT_B_Face_15",
-                    ])),
-                    Rarity::Rare => Some(pick_random(&[
-                        "NFT_B_Face_9",
-                        "NFT_B_Face_10",
-                        "NFT_B_Face_11",
-                        "NFT_B_Face_12",
-                    ])),
-                    Rarity::Epic => Some(pick_random(&[
-                        "NFT_B_Face_16",
-                        "NFT_B_Face_17",
-                        "NFT_B_Face_18",
-                        "NFT_B_Face_19",
-                        "NFT_B_Face_23",
-                        "NFT_B_Face_24",
-                    ])),
-                    Rarity::Legendary => Some(pick_random(&[
-                        "NFT_B_Face_20",
-                        "NFT_B_Face_21",
-                        "NFT_B_Face_22",
-                        "NFT_B_Face_25",
-                        "NFT_B_Face_27",
-                    ])),
-                    Rarity::Mythical => Some(pick_random(&["NFT_B_Face_26", "NFT_B_Face_28"])),
-                }
-            }
-            Gender::Girl => {
-                let rarity = Rarity::pick_random_rarity();
-
-                match rarity {
-                    Rarity::Common => Some(pick_random(&[
-                        "NFT_G_Face_6",
-                        "NFT_G_Face_7",
-                        "NFT_G_Face_8",
-                        "NFT_G_Face_9",
-                        "NFT_G_Face_14",
-                    ])),
-                    Rarity::Uncommon => Some(pick_random(&[
-                        "NFT_G_Face_1",
-                        "NFT_G_Face_2",
-                        "NFT_G_Face_3",
-                        "NFT_G_Face_4",
-                        "NFT_G_Face_5",
-                    ])),
-                    Rarity::Rare => Some(pick_random(&[
-                        "NFT_G_Face_10",
-                        "NFT_G_Face_11",
-                        "NFT_G_Face_12",
-                        "NFT_G_Face_13",
-                    ])),
-                    Rarity::Epic => Some(pick_random(&[
-                        "NFT_G_Face_15",
-                        "NFT_G_Face_16",
-                        "NFT_G_Face_17",
-                    ])),
-                    Rarity::Legendary => Some(pick_random(&[
-                        "NFT_G_Face_18",
-                        "NFT_G_Face_19",
-                        "NFT_G_Face_21",
-                        "NFT_G_Face_22",
-                    ])),
-                    Rarity::Mythical => Some(pick_random(&["NFT_G_Face_20", "NFT_G_Face_23"])),
-                }
-            }
+            Gender::Boy => [
+                // Common
+                &[
+                    "NFT_B_Face_1",
+                    "NFT_B_Face_2",
+                    "NFT_B_Face_3",
+                    "NFT_B_Face_4",
+                    "NFT_B_Face_13",
+                    "NFT_B_Face_14",
+                ],
+                // Uncommon
+                &[
+                    "NFT_B_Face_5",
+                    "NFT_B_Face_6",
+                    "NFT_B_Face_7",
+                    "NFT_B_Face_8",
+                    "NFT_B_Face_15",
+                ],
+                // Rare
+                &[
+                    "NFT_B_Face_9",
+                    "NFT_B_Face_10",
+                    "NFT_B_Face_11",
+                    "NFT_B_Face_12",
+                ],
+                // Epic
+                &[
+                    "NFT_B_Face_16",
+                    "NFT_B_Face_17",
+                    "NFT_B_Face_18",
+                    "NFT_B_Face_19",
+                    "NFT_B_Face_23",
+                    "NFT_B_Face_24",
+                ],
+                // Legendary
+                &[
+                    "NFT_B_Face_20",
+                    "NFT_B_Face_21",
+                    "NFT_B_Face_22",
+                    "NFT_B_Face_25",
+                    "NFT_B_Face_27",
+                ],
+                // Mythical
+                &["NFT_B_Face_26", "NFT_B_Face_28"],
+            ],
+            Gender::Girl => [
+                // Common
+                &[
+                    "NFT_G_Face_6",
+                    "NFT_G_Face_7",
+                    "NFT_G_Face_8",
+                    "NFT_G_Face_9",
+                    "NFT_G_Face_14",
+                ],
+                // Uncommon
+                &[
+                    "NFT_G_Face_1",
+                    "NFT_G_Face_2",
+                    "NFT_G_Face_3",
+                    "NFT_G_Face_4",
+                    "NFT_G_Face_5",
+                ],
+                // Rare
+                &[
+                    "NFT_G_Face_10",
+                    "NFT_G_Face_11",
+                    "NFT_G_Face_12",
+                    "NFT_G_Face_13",
+                ],
+                // Epic
+                &["NFT_G_Face_15", "NFT_G_Face_16", "NFT_G_Face_17"],
+                // Legendary
+                &[
+                    "NFT_G_Face_18",
+                    "NFT_G_Face_19",
+                    "NFT_G_Face_21",
+                    "NFT_G_Face_22",
+                ],
+                // Mythical
+                &["NFT_G_Face_20", "NFT_G_Face_23"],
+            ],
         }
     }
 }
@@ -497,155 +1176,292 @@ impl RandomizedPart for Face {
 struct FaceAcc {}
 
 impl RandomizedPart for FaceAcc {
-    fn random_part(_gender: Gender) -> Option<&'static str> {
-        let rarity = Rarity::pick_random_rarity();
-
-        match rarity {
-            Rarity::Common => None,
-            Rarity::Uncommon => Some(pick_random(&["NFT_Face_Acc_1", "NFT_Face_Acc_2"])),
-            Rarity::Rare => Some(pick_random(&["NFT_Face_Acc_5", "NFT_Face_Acc_6"])),
-            Rarity::Epic => Some(pick_random(&["NFT_Face_Acc_3", "NFT_Face_Acc_4"])),
-            Rarity::Legendary => Some(pick_random(&["NFT_Face_Acc_7", "NFT_Face_Acc_8"])),
-            Rarity::Mythical => Some(pick_random(&["NFT_Face_Acc_9"])),
-        }
+    fn tiers(_gender: Gender) -> Tiers {
+        [
+            // Common
+            &[],
+            // Uncommon
+            &["NFT_Face_Acc_1", "NFT_Face_Acc_2"],
+            // Rare
+            &["NFT_Face_Acc_5", "NFT_Face_Acc_6"],
+            // Epic
+            &["NFT_Face_Acc_3", "NFT_Face_Acc_4"],
+            // Legendary
+            &["NFT_Face_Acc_7", "NFT_Face_Acc_8"],
+            // Mythical
+            &["NFT_Face_Acc_9"],
+        ]
     }
 }
 
-struct Hair {}
-
-impl Hair {
-    fn from_hair_long(hair_long: Option<&str>) -> Option<&'static str> {
-        if let Some(hair_long) = hair_long {
-            let mut arr: Vec<String> = hair_long
-                .to_string()
-                .split("_")
-                .map(|item| item.to_string())
-                .collect();
-            arr.remove(arr.len() - 2);
+// The front hair drawn over the face with each long hair, which stays
+// behind the body.
+const HAIR_PAIRS: &[(&str, &str)] = &[
+    ("NFT_B_Hair_Long_1", "NFT_B_Hair_1"),
+    ("NFT_B_Hair_Long_2", "NFT_B_Hair_2"),
+    ("NFT_B_Hair_Long_3", "NFT_B_Hair_3"),
+    ("NFT_B_Hair_Long_4", "NFT_B_Hair_4"),
+    ("NFT_B_Hair_Long_5", "NFT_B_Hair_5"),
+    ("NFT_B_Hair_Long_6", "NFT_B_Hair_6"),
+    ("NFT_B_Hair_Long_7", "NFT_B_Hair_7"),
+    ("NFT_B_Hair_Long_8", "NFT_B_Hair_8"),
+    ("NFT_B_Hair_Long_9", "NFT_B_Hair_9"),
+    ("NFT_B_Hair_Long_10", "NFT_B_Hair_10"),
+    ("NFT_B_Hair_Long_11", "NFT_B_Hair_11"),
+    ("NFT_B_Hair_Long_12", "NFT_B_Hair_12"),
+    ("NFT_B_Hair_Long_13", "NFT_B_Hair_13"),
+    ("NFT_B_Hair_Long_14", "NFT_B_Hair_14"),
+    ("NFT_B_Hair_Long_15", "NFT_B_Hair_15"),
+    ("NFT_B_Hair_Long_16", "NFT_B_Hair_16"),
+    ("NFT_B_Hair_Long_17", "NFT_B_Hair_17"),
+    ("NFT_B_Hair_Long_18", "NFT_B_Hair_18"),
+    ("NFT_B_Hair_Long_19", "NFT_B_Hair_19"),
+    ("NFT_B_Hair_Long_20", "NFT_B_Hair_20"),
+    ("NFT_B_Hair_Long_21", "NFT_B_Hair_21"),
+    ("NFT_B_Hair_Long_22", "NFT_B_Hair_22"),
+    ("NFT_B_Hair_Long_23", "NFT_B_Hair_23"),
+    ("NFT_B_Hair_Long_24", "NFT_B_Hair_24"),
+    ("NFT_B_Hair_Long_25", "NFT_B_Hair_25"),
+    ("NFT_B_Hair_Long_26", "NFT_B_Hair_26"),
+    ("NFT_B_Hair_Long_27", "NFT_B_Hair_27"),
+    ("NFT_B_Hair_Long_28", "NFT_B_Hair_28"),
+    ("NFT_B_Hair_Long_29", "NFT_B_Hair_29"),
+    ("NFT_B_Hair_Long_30", "NFT_B_Hair_30"),
+    ("NFT_B_Hair_Long_31", "NFT_B_Hair_31"),
+    ("NFT_B_Hair_Long_32", "NFT_B_Hair_32"),
+    ("NFT_B_Hair_Long_33", "NFT_B_Hair_33"),
+    ("NFT_B_Hair_Long_34", "NFT_B_Hair_34"),
+    ("NFT_B_Hair_Long_35", "NFT_B_Hair_35"),
+    ("NFT_B_Hair_Long_36", "NFT_B_Hair_36"),
+    ("NFT_B_Hair_Long_37", "NFT_B_Hair_37"),
+    ("NFT_B_Hair_Long_38", "NFT_B_Hair_38"),
+    ("NFT_B_Hair_Long_39", "NFT_B_Hair_39"),
+    ("NFT_B_Hair_Long_40", "NFT_B_Hair_40"),
+    ("NFT_B_Hair_Long_41", "NFT_B_Hair_41"),
+    ("NFT_B_Hair_Long_42", "NFT_B_Hair_42"),
+    ("NFT_G_Hair_Long_1", "NFT_G_Hair_1"),
+    ("NFT_G_Hair_Long_2", "NFT_G_Hair_2"),
+    ("NFT_G_Hair_Long_3", "NFT_G_Hair_3"),
+    ("NFT_G_Hair_Long_4", "NFT_G_Hair_4"),
+    ("NFT_G_Hair_Long_5", "NFT_G_Hair_5"),
+    ("NFT_G_Hair_Long_6", "NFT_G_Hair_6"),
+    ("NFT_G_Hair_Long_7", "NFT_G_Hair_7"),
+    ("NFT_G_Hair_Long_8", "NFT_G_Hair_8"),
+    ("NFT_G_Hair_Long_9", "NFT_G_Hair_9"),
+    ("NFT_G_Hair_Long_10", "NFT_G_Hair_10"),
+    ("NFT_G_Hair_Long_11", "NFT_G_Hair_11"),
+    ("NFT_G_Hair_Long_12", "NFT_G_Hair_12"),
+    ("NFT_G_Hair_Long_13", "NFT_G_Hair_13"),
+    ("NFT_G_Hair_Long_14", "NFT_G_Hair_14"),
+    ("NFT_G_Hair_Long_15", "NFT_G_Hair_15"),
+    ("NFT_G_Hair_Long_16", "NFT_G_Hair_16"),
+    ("NFT_G_Hair_Long_17", "NFT_G_Hair_17"),
+    ("NFT_G_Hair_Long_18", "NFT_G_Hair_18"),
+    ("NFT_G_Hair_Long_19", "NFT_G_Hair_19"),
+    ("NFT_G_Hair_Long_20", "NFT_G_Hair_20"),
+    ("NFT_G_Hair_Long_21", "NFT_G_Hair_21"),
+    ("NFT_G_Hair_Long_22", "NFT_G_Hair_22"),
+    ("NFT_G_Hair_Long_23", "NFT_G_Hair_23"),
+    ("NFT_G_Hair_Long_24", "NFT_G_Hair_24"),
+    ("NFT_G_Hair_Long_25", "NFT_G_Hair_25"),
+    ("NFT_G_Hair_Long_26", "NFT_G_Hair_26"),
+    ("NFT_G_Hair_Long_27", "NFT_G_Hair_27"),
+    ("NFT_G_Hair_Long_28", "NFT_G_Hair_28"),
+    ("NFT_G_Hair_Long_29", "NFT_G_Hair_29"),
+    ("NFT_G_Hair_Long_30", "NFT_G_Hair_30"),
+    ("NFT_G_Hair_Long_31", "NFT_G_Hair_31"),
+    ("NFT_G_Hair_Long_32", "NFT_G_Hair_32"),
+    ("NFT_G_Hair_Long_33", "NFT_G_Hair_33"),
+    ("NFT_G_Hair_Long_34", "NFT_G_Hair_34"),
+    ("NFT_G_Hair_Long_35", "NFT_G_Hair_35"),
+    ("NFT_G_Hair_Long_36", "NFT_G_Hair_36"),
+    ("NFT_G_Hair_Long_37", "NFT_G_Hair_37"),
+    ("NFT_G_Hair_Long_38", "NFT_G_Hair_38"),
+    ("NFT_G_Hair_Long_39", "NFT_G_Hair_39"),
+    ("NFT_G_Hair_Long_40", "NFT_G_Hair_40"),
+    ("NFT_G_Hair_Long_41", "NFT_G_Hair_41"),
+    ("NFT_G_Hair_Long_42", "NFT_G_Hair_42"),
+    ("NFT_G_Hair_Long_43", "NFT_G_Hair_43"),
+    ("NFT_G_Hair_Long_44", "NFT_G_Hair_44"),
+    ("NFT_G_Hair_Long_45", "NFT_G_Hair_45"),
+    ("NFT_G_Hair_Long_46", "NFT_G_Hair_46"),
+];
+
+// The layer the conditional layers depend on, and the linked layers follow.
+const ANCHOR_LAYER: &str = "HairLong";
+
+// A layer drawn as another image part of the trait of the layer it follows,
+// at its own z-level. It has no trait of its own: its variant is the one
+// paired with the variant of the followed layer.
+struct Link {
+    layer: &'static str,
+    follows: &'static str,
+    // (followed variant, linked variant)
+    pairs: &'static [(&'static str, &'static str)],
+}
 
-            let s = arr.join("_");
-            let result = Box::leak(s.into_boxed_str());
-            Some(result)
-        } else {
-            None
+const LINKS: &[Link] = &[Link {
+    layer: "Hair",
+    follows: "HairLong",
+    pairs: HAIR_PAIRS,
+}];
+
+impl Link {
+    // The layer `layer` follows, if it is linked in the built-in catalog or
+    // the catalog file.
+    fn follows(layer: &str) -> Option<&'static str> {
+        match LINKS.iter().find(|link| link.layer == layer) {
+            Some(link) => Some(link.follows),
+            None => catalog::get().link(layer).map(|link| link.follows.as_str()),
         }
     }
+
+    // The variant of the linked `layer` paired with `variant` of the layer
+    // it follows.
+    fn linked(layer: &str, variant: &str) -> Option<&'static str> {
+        LINKS
+            .iter()
+            .filter(|link| link.layer == layer)
+            .flat_map(|link| link.pairs)
+            .find(|(followed, _)| *followed == variant)
+            .map(|(_, linked)| *linked)
+            .or_else(|| {
+                catalog::get()
+                    .link(layer)?
+                    .pairs
+                    .get(variant)
+                    .map(String::as_str)
+            })
+    }
+
+    // The followed variants paired by the built-in catalog and the catalog
+    // file.
+    fn followed(layer: &str) -> impl Iterator<Item = &'static str> + '_ {
+        LINKS
+            .iter()
+            .filter(move |link| link.layer == layer)
+            .flat_map(|link| link.pairs.iter().map(|(followed, _)| *followed))
+            .chain(
+                catalog::get()
+                    .link(layer)
+                    .into_iter()
+                    .flat_map(|link| link.pairs.keys().map(String::as_str)),
+            )
+    }
 }
+
+struct Hair {}
 impl RandomizedPart for Hair {
-    fn random_part(gender: Gender) -> Option<&'static str> {
+    fn tiers(gender: Gender) -> Tiers {
         match gender {
-            Gender::Boy => {
-                let rarity = Rarity::pick_random_rarity();
-
-                match rarity {
-                    Rarity::Common => Some(pick_random(&[
-                        "NFT_B_Hair_11",
-                        "NFT_B_Hair_12",
-                        "NFT_B_Hair_13",
-                        "NFT_B_Hair_14",
-                        "NFT_B_Hair_15",
-                        "NFT_B_Hair_16",
-                        "NFT_B_Hair_17",
-                        "NFT_B_Hair_18",
-                        "NFT_B_Hair_19",
-                        "NFT_B_Hair_20",
-                        "NFT_B_Hair_21",
-                        "NFT_B_Hair_22",
-                        "NFT_B_Hair_23",
-                        "NFT_B_Hair_24",
-                        "NFT_B_Hair_25",
-                        "NFT_B_Hair_26",
-                        "NFT_B_Hair_27",
-                        "NFT_B_Hair_28",
-                        "NFT_B_Hair_29",
-                        "NFT_B_Hair_30",
-                        "NFT_B_Hair_31",
-                    ])),
-                    Rarity::Uncommon => Some(pick_random(&[
-                        "NFT_B_Hair_2",
-                        "NFT_B_Hair_3",
-                        "NFT_B_Hair_4",
-                        "NFT_B_Hair_7",
-                        "NFT_B_Hair_8",
-                        "NFT_B_Hair_9",
-                        "NFT_B_Hair_10",
-                    ])),
-                    Rarity::Rare => Some(pick_random(&[
-                        "NFT_B_Hair_32",
-                        "NFT_B_Hair_33",
-                        "NFT_B_Hair_34",
-                        "NFT_B_Hair_35",
-                        "NFT_B_Hair_36",
-                        "NFT_B_Hair_37",
-                        "NFT_B_Hair_38",
-                    ])),
-                    Rarity::Epic => Some(pick_random(&[
-                        "NFT_B_Hair_1",
-                        "NFT_B_Hair_5",
-                        "NFT_B_Hair_6",
-                    ])),
-                    Rarity::Legendary => Some(pick_random(&["NFT_B_Hair_39", "NFT_B_Hair_41"])),
-                    Rarity::Mythical => Some(pick_random(&["NFT_B_Hair_40", "NFT_B_Hair_42"])),
-                }
-            }
-            Gender::Girl => {
-                let rarity = Rarity::pick_random_rarity();
-
-                match rarity {
-                    Rarity::Common => Some(pick_random(&[
-                        "NFT_G_Hair_17",
-                        "NFT_G_Hair_18",
-                        "NFT_G_Hair_19",
-                        "NFT_G_Hair_20",
-                        "NFT_G_Hair_21",
-                        "NFT_G_Hair_22",
-                        "NFT_G_Hair_23",
-                        "NFT_G_Hair_24",
-                        "NFT_G_Hair_25",
-                        "NFT_G_Hair_26",
-                        "NFT_G_Hair_27",
-                        "NFT_G_Hair_28",
-                        "NFT_G_Hair_29",
-                        "NFT_G_Hair_30",
-                        "NFT_G_Hair_31",
-                    ])),
-                    Rarity::Uncommon => Some(pick_random(&[
-                        "NFT_G_Hair_4",
-                        "NFT_G_Hair_5",
-                        "NFT_G_Hair_6",
-                        "NFT_G_Hair_7",
-                        "NFT_G_Hair_8",
-                        "NFT_G_Hair_9",
-                        "NFT_G_Hair_10",
-                        "NFT_G_Hair_13",
-                        "NFT_G_Hair_14",
-                        "NFT_G_Hair_15",
-                        "NFT_G_Hair_16",
-                        "NFT_G_Hair_32",
-                        "NFT_G_Hair_33",
-                        "NFT_G_Hair_37",
-                        "NFT_G_Hair_38",
-                        "NFT_G_Hair_39",
-                        "NFT_G_Hair_40",
-                        "NFT_G_Hair_41",
-                        "NFT_G_Hair_42",
-                    ])),
-                    Rarity::Rare => Some(pick_random(&[
-                        "NFT_G_Hair_34",
-                        "NFT_G_Hair_35",
-                        "NFT_G_Hair_36",
-                    ])),
-                    Rarity::Epic => Some(pick_random(&[
-                        "NFT_G_Hair_1",
-                        "NFT_G_Hair_2",
-                        "NFT_G_Hair_3",
-                        "NFT_G_Hair_11",
-                        "NFT_G_Hair_12",
-                    ])),
-                    Rarity::Legendary => Some(pick_random(&["NFT_G_Hair_43", "NFT_G_Hair_45"])),
-                    Rarity::Mythical => Some(pick_random(&["NFT_G_Hair_44", "NFT_G_Hair_46"])),
-                }
-            }
+            Gender::Boy => [
+                // Common
+                &[
+                    "NFT_B_Hair_11",
+                    "NFT_B_Hair_12",
+                    "NFT_B_Hair_13",
+                    "NFT_B_Hair_14",
+                    "NFT_B_Hair_15",
+                    "NFT_B_Hair_16",
+                    "NFT_B_Hair_17",
+                    "NFT_B_Hair_18",
+                    "NFT_B_Hair_19",
+                    "NFT_B_Hair_20",
+                    "NFT_B_Hair_21",
+                    "NFT_B_Hair_22",
+                    "NFT_B_Hair_23",
+                    "NFT_B_Hair_24",
+                    "NFT_B_Hair_25",
+                    "NFT_B_Hair_26",
+                    "NFT_B_Hair_27",
+                    "NFT_B_Hair_28",
+                    "NFT_B_Hair_29",
+                    "NFT_B_Hair_30",
+                    "NFT_B_Hair_31",
+                ],
+                // Uncommon
+                &[
+                    "NFT_B_Hair_2",
+                    "NFT_B_Hair_3",
+                    "NFT_B_Hair_4",
+                    "NFT_B_Hair_7",
+                    "NFT_B_Hair_8",
+                    "NFT_B_Hair_9",
+                    "NFT_B_Hair_10",
+                ],
+                // Rare
+                &[
+                    "NFT_B_Hair_32",
+                    "NFT_B_Hair_33",
+                    "NFT_B_Hair_34",
+                    "NFT_B_Hair_35",
+                    "NFT_B_Hair_36",
+                    "NFT_B_Hair_37",
+                    "NFT_B_Hair_38",
+                ],
+                // Epic
+                &["NFT_B_Hair_1", "NFT_B_Hair_5", "NFT_B_Hair_6"],
+                // Legendary
+                &["NFT_B_Hair_39", "NFT_B_Hair_41"],
+                // Mythical
+                &["NFT_B_Hair_40", "NFT_B_Hair_42"],
+            ],
+            Gender::Girl => [
+                // Common
+                &[
+                    "NFT_G_Hair_17",
+                    "NFT_G_Hair_18",
+                    "NFT_G_Hair_19",
+                    "NFT_G_Hair_20",
+                    "NFT_G_Hair_21",
+                    "NFT_G_Hair_22",
+                    "NFT_G_Hair_23",
+                    "NFT_G_Hair_24",
+                    "NFT_G_Hair_25",
+                    "NFT_G_Hair_26",
+                    "NFT_G_Hair_27",
+                    "NFT_G_Hair_28",
+                    "NFT_G_Hair_29",
+                    "NFT_G_Hair_30",
+                    "NFT_G_Hair_31",
+                ],
+                // Uncommon
+                &[
+                    "NFT_G_Hair_4",
+                    "NFT_G_Hair_5",
+                    "NFT_G_Hair_6",
+                    "NFT_G_Hair_7",
+                    "NFT_G_Hair_8",
+                    "NFT_G_Hair_9",
+                    "NFT_G_Hair_10",
+                    "NFT_G_Hair_13",
+                    "NFT_G_Hair_14",
+                    "NFT_G_Hair_15",
+                    "NFT_G_Hair_16",
+                    "NFT_G_Hair_32",
+                    "NFT_G_Hair_33",
+                    "NFT_G_Hair_37",
+                    "NFT_G_Hair_38",
+                    "NFT_G_Hair_39",
+                    "NFT_G_Hair_40",
+                    "NFT_G_Hair_41",
+                    "NFT_G_Hair_42",
+                ],
+                // Rare
+                &["NFT_G_Hair_34", "NFT_G_Hair_35", "NFT_G_Hair_36"],
+                // Epic
+                &[
+                    "NFT_G_Hair_1",
+                    "NFT_G_Hair_2",
+                    "NFT_G_Hair_3",
+                    "NFT_G_Hair_11",
+                    "NFT_G_Hair_12",
+                ],
+                // Legendary
+                &["NFT_G_Hair_43", "NFT_G_Hair_45"],
+                // Mythical
+                &["NFT_G_Hair_44", "NFT_G_Hair_46"],
+            ],
         }
     }
 }
@@ -653,27 +1469,43 @@ impl RandomizedPart for Hair {
 struct HeadPhone {}
 
 impl RandomizedPart for HeadPhone {
-    fn random_part(_gender: Gender) -> Option<&'static str> {
-        let rarity = Rarity::pick_random_rarity();
-
-        match rarity {
-            Rarity::Common => None,
-            Rarity::Uncommon => Some(pick_random(&["NFT_Head_Phone_1"])),
-            Rarity::Rare => Some(pick_random(&["NFT_Head_Phone_2"])),
-            Rarity::Epic => Some(pick_random(&["NFT_Head_Phone_3"])),
-            Rarity::Legendary => Some(pick_random(&["NFT_Head_Phone_4"])),
-            Rarity::Mythical => Some(pick_random(&["NFT_Head_Phone_5"])),
-        }
+    fn tiers(_gender: Gender) -> Tiers {
+        [
+            // Common
+            &[],
+            // Uncommon
+            &["NFT_Head_Phone_1"],
+            // Rare
+            &["NFT_Head_Phone_2"],
+            // Epic
+            &["NFT_Head_Phone_3"],
+            // Legendary
+            &["NFT_Head_Phone_4"],
+            // Mythical
+            &["NFT_Head_Phone_5"],
+        ]
     }
 }
 
-fn pick_random<'a>(choices: &[&'a str]) -> &'a str {
-    let weights = choices.iter().map(|_| 1).collect::<Vec<i64>>();
+// Roll a rarity, then one of the catalog sets of that rarity. Most rarities
+// have no set.
+fn random_set(gender: Gender) -> Option<&'static catalog::Set> {
+    let mut rng = thread_rng();
+    let rarity = Rarity::pick_random_rarity();
 
-    let dist = WeightedIndex::new(&weights).expect("Error parsing weights");
+    let sets = catalog::get()
+        .sets(gender)
+        .iter()
+        .filter(|set| set.rarity == rarity)
+        .collect::<Vec<&catalog::Set>>();
+    sets.choose(&mut rng).copied()
+}
+
+// Uniformly pick one of the variants, or none if the rarity has no variant.
+fn pick_random<'a>(choices: &[&'a str]) -> Option<&'a str> {
     let mut rng = thread_rng();
 
-    choices[dist.sample(&mut rng)]
+    choices.choose(&mut rng).copied()
 }
 
 enum Parts {
@@ -688,7 +1520,7 @@ enum Parts {
     HeadPhone(HeadPhone),
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
 enum Rarity {
     Common,
     Uncommon,
@@ -710,21 +1542,524 @@ impl Rarity {
         }
     }
 
+    fn name(&self) -> &'static str {
+        match self {
+            Rarity::Common => "Common",
+            Rarity::Uncommon => "Uncommon",
+            Rarity::Rare => "Rare",
+            Rarity::Epic => "Epic",
+            Rarity::Legendary => "Legendary",
+            Rarity::Mythical => "Mythical",
+        }
+    }
+
+    // Border and badge drawn around a metronion of this rarity.
+    fn frame(&self) -> frames::Frame {
+        let (colour, width, badge) = match self {
+            Rarity::Common => ("#9e9e9e", 8, None),
+            Rarity::Uncommon => ("#43a047", 10, None),
+            Rarity::Rare => ("#1e88e5", 12, None),
+            Rarity::Epic => ("#8e24aa", 16, Some("#e1bee7")),
+            Rarity::Legendary => ("#fb8c00", 20, Some("#ffe0b2")),
+            Rarity::Mythical => ("#e53935", 24, Some("#ffd700")),
+        };
+        frames::Frame {
+            colour,
+            width,
+            badge,
+        }
+    }
+
+    const ALL: [Rarity; 6] = [
+        Rarity::Common,
+        Rarity::Uncommon,
+        Rarity::Rare,
+        Rarity::Epic,
+        Rarity::Legendary,
+        Rarity::Mythical,
+    ];
+
     fn pick_random_rarity() -> Self {
-        let choices = [
-            Rarity::Common,
-            Rarity::Uncommon,
-            Rarity::Rare,
-            Rarity::Epic,
-            Rarity::Legendary,
-            Rarity::Mythical,
-        ];
-        let weights: Vec<f64> = choices.iter().map(|item| item.probability()).collect();
-        let dist = WeightedIndex::new(&weights).expect("Error parsing weights");
+        let choices = Rarity::ALL;
         let mut rng = thread_rng();
+        let total: f64 = choices.iter().map(|item| item.probability()).sum();
+        let mut roll = rng.gen::<f64>() * total;
+
+        for rarity in choices {
+            if roll < rarity.probability() {
+                return rarity;
+            }
+            roll -= rarity.probability();
+        }
+        Rarity::Mythical
+    }
+}
+
+// Variants of each rarity per layer name, for boys and girls.
+static TIERS: [OnceLock<HashMap<&str, CatalogTiers>>; 2] = [OnceLock::new(), OnceLock::new()];
+
+impl Parts {
+    fn layer_name(&self) -> &'static str {
+        match self {
+            Parts::Background(_) => "Background",
+            Parts::Hand(_) => "Hand",
+            Parts::HairLong(_) => "HairLong",
+            Parts::Body(_) => "Body",
+            Parts::Clothes(_) => "Clothes",
+            Parts::Face(_) => "Face",
+            Parts::FaceAcc(_) => "FaceAcc",
+            Parts::Hair(_) => "Hair",
+            Parts::HeadPhone(_) => "HeadPhone",
+        }
+    }
+
+    fn built_in_tiers(&self, gender: Gender) -> Tiers {
+        match self {
+            Parts::Background(_) => Background::tiers(gender),
+            Parts::Hand(_) => Hand::tiers(gender),
+            Parts::HairLong(_) => HairLong::tiers(gender),
+            Parts::Body(_) => Body::tiers(gender),
+            Parts::Clothes(_) => Clothes::tiers(gender),
+            Parts::Face(_) => Face::tiers(gender),
+            Parts::FaceAcc(_) => FaceAcc::tiers(gender),
+            Parts::Hair(_) => Hair::tiers(gender),
+            Parts::HeadPhone(_) => HeadPhone::tiers(gender),
+        }
+    }
+
+    // The variants of each rarity, built once per gender as they are sampled
+    // for every metronion. The catalog must be installed first.
+    fn tiers(&self, gender: Gender) -> &'static CatalogTiers {
+        let tiers = TIERS[gender as usize].get_or_init(|| {
+            get_parts_order()
+                .iter()
+                .map(|part| (part.layer_name(), part.catalog_tiers(gender)))
+                .collect()
+        });
+        &tiers[self.layer_name()]
+    }
+
+    // The built-in variants of each rarity, with the colour variants of the
+    // catalog file recoloured from them and the procedural backgrounds it
+    // opts in to.
+    fn catalog_tiers(&self, gender: Gender) -> CatalogTiers {
+        let built_in = self.built_in_tiers(gender);
+        let mut tiers = built_in.map(<[&str]>::to_vec);
+        for (variant, colour) in catalog::get().colour_variants() {
+            if built_in
+                .iter()
+                .any(|tier| tier.contains(&colour.base.as_str()))
+            {
+                tiers[colour.rarity as usize].push(variant);
+            }
+        }
+        if let Parts::Background(_) = self {
+            for (variant, rarity) in catalog::get().procedural_backgrounds() {
+                tiers[rarity as usize].push(variant);
+            }
+        }
+        tiers
+    }
+
+    // Roll a rarity, then one of its variants. A rarity without variants
+    // yields no part.
+    fn random_part(&self, gender: Gender) -> Option<&'static str> {
+        pick_random(&self.tiers(gender)[Rarity::pick_random_rarity() as usize])
+    }
+
+    // Layers sampled until they yield a variant, the others may be left out
+    // or depend on the long hair.
+    fn is_required(&self) -> bool {
+        !matches!(self, Parts::FaceAcc(_) | Parts::HeadPhone(_))
+            && Link::follows(self.layer_name()).is_none()
+    }
+
+    // Trait the variant of the layer is recorded as in the metadata.
+    fn trait_type(&self) -> &'static str {
+        match self {
+            Parts::Background(_) => "Background",
+            Parts::Hand(_) => "Hand",
+            Parts::HairLong(_) | Parts::Hair(_) => "Hair",
+            Parts::Body(_) => "Body",
+            Parts::Clothes(_) => "Clothes",
+            Parts::Face(_) => "Face",
+            Parts::FaceAcc(_) => "Face Accessory",
+            Parts::HeadPhone(_) => "Headphone",
+        }
+    }
+}
+
+fn log_stats(gender: Gender, stats: &stats::Stats, count: usize) {
+    info!(
+        "{gender}: {} distinct metronions, expected supply out of {count}",
+        stats.combinations
+    );
+    for set in &stats.sets {
+        info!(
+            "{gender} {:<10} {:<20} {:<9} {:>8.4}% {:>8.1}",
+            "Set",
+            set.set,
+            set.rarity,
+            set.probability * 100.0,
+            set.probability * count as f64
+        );
+    }
+    for layer in &stats.layers {
+        if layer.none > 0.0 {
+            info!(
+                "{gender} {:<10} {:<20} {:<9} {:>8.4}% {:>8.1}",
+                layer.layer,
+                "(none)",
+                "",
+                layer.none * 100.0,
+                layer.none * count as f64
+            );
+        }
+        for variant in &layer.variants {
+            info!(
+                "{gender} {:<10} {:<20} {:<9} {:>8.4}% {:>8.1}",
+                layer.layer,
+                variant.variant,
+                variant.rarity,
+                variant.probability * 100.0,
+                variant.probability * count as f64
+            );
+        }
+    }
+}
+
+fn log_simulation(gender: Gender, simulation: &simulate::Simulation) {
+    info!(
+        "{gender}: supply over {} collections of {} as mean [min p5 p50 p95 max]",
+        simulation.runs, simulation.count
+    );
+    for variant in &simulation.variants {
+        info!(
+            "{gender} {:<10} {:<20} {:<9} {:>8.1} [{} {} {} {} {}]",
+            variant.layer,
+            variant.variant,
+            variant.rarity,
+            variant.mean,
+            variant.min,
+            variant.p5,
+            variant.p50,
+            variant.p95,
+            variant.max
+        );
+    }
+    info!(
+        "{gender}: {:.2}% of the collections have a {} variant without supply",
+        simulation.any_watched_zero * 100.0,
+        simulation.watched_rarity
+    );
+}
+
+// Check every variant of a followed layer has one linked variant of the
+// same gender and rarity, every linked variant follows one, and every pair
+// names a variant of the followed layer once.
+fn validate_links() -> Result<()> {
+    for part in get_parts_order() {
+        let layer = part.layer_name();
+        let Some(follows) = Link::follows(layer) else {
+            continue;
+        };
+        let followed_part = layer_named(follows).expect("Followed layer");
+        let mut paired = HashSet::new();
+        for gender in [Gender::Boy, Gender::Girl] {
+            let rarity_of = rarities(gender);
+            let followed = followed_part.tiers(gender).concat();
+            let linked = part.tiers(gender).concat();
+            for variant in &followed {
+                let Some(linked_variant) = Link::linked(layer, variant) else {
+                    return Err(Error::Catalog(format!(
+                        "{follows} {variant} has no linked {layer}"
+                    )));
+                };
+                if !linked.contains(&linked_variant) {
+                    return Err(Error::Catalog(format!(
+                        "{follows} {variant} is paired with {linked_variant}, which is not a {gender} {layer}"
+                    )));
+                }
+                if rarity_of[linked_variant] != rarity_of[variant] {
+                    return Err(Error::Catalog(format!(
+                        "{follows} {variant} is {} but its {layer} {linked_variant} is {}",
+                        rarity_of[variant].name(),
+                        rarity_of[linked_variant].name()
+                    )));
+                }
+                paired.insert(*variant);
+            }
+            for variant in &linked {
+                if !followed
+                    .iter()
+                    .any(|followed| Link::linked(layer, followed) == Some(variant))
+                {
+                    return Err(Error::Catalog(format!(
+                        "{layer} {variant} follows no {gender} {follows}"
+                    )));
+                }
+            }
+        }
+        let mut listed = HashSet::new();
+        if let Some(variant) = Link::followed(layer)
+            .find(|variant| !paired.contains(variant) || !listed.insert(*variant))
+        {
+            return Err(Error::Catalog(format!(
+                "the {layer} links list {follows} {variant} twice or outside the catalog"
+            )));
+        }
+    }
+    Ok(())
+}
 
-        choices[dist.sample(&mut rng)]
+// Check the pinned metronions wear no banned combination, and each linked
+// layer the variant paired with the one of the layer it follows.
+fn validate_pinned(
+    pinned_file: &Path,
+    pinned: &BTreeMap<usize, PinnedToken>,
+    rules: &Rules,
+    layer_of: impl Fn(&str) -> Option<&'static str>,
+) -> Result<()> {
+    for (id, token) in pinned {
+        let Pin::Parts(parts) = &token.pin else {
+            continue;
+        };
+        let layers = parts
+            .iter()
+            .filter_map(|part| Some((layer_of(part)?, part.as_str())))
+            .collect::<Vec<(&str, &str)>>();
+        if rules.is_banned(&layers) {
+            return Err(Error::Validation(format!(
+                "{pinned_file:?}: metronion {id} wears a banned combination"
+            )));
+        }
+
+        let variant = |name: &str| {
+            layers
+                .iter()
+                .find(|(layer, _)| *layer == name)
+                .map(|(_, variant)| *variant)
+        };
+        for part in get_parts_order() {
+            let layer = part.layer_name();
+            let Some(follows) = Link::follows(layer) else {
+                continue;
+            };
+            match (variant(follows), variant(layer)) {
+                (Some(followed), linked) if linked != Link::linked(layer, followed) => {
+                    return Err(Error::Validation(format!(
+                        "{pinned_file:?}: metronion {id} must wear {layer} {} with {follows} {followed}",
+                        Link::linked(layer, followed).unwrap_or_default()
+                    )));
+                }
+                (None, Some(linked)) => {
+                    return Err(Error::Validation(format!(
+                        "{pinned_file:?}: metronion {id} wears {layer} {linked} without the {follows} it follows"
+                    )));
+                }
+                _ => {}
+            }
+        }
     }
+    Ok(())
+}
+
+// Check the catalog file against the built-in catalog.
+fn validate_catalog(catalog_file: &Path, catalog: &catalog::Catalog) -> Result<()> {
+    let built_in_layer = |variant: &str| {
+        get_parts_order().into_iter().find(|part| {
+            [Gender::Boy, Gender::Girl].into_iter().any(|gender| {
+                part.built_in_tiers(gender)
+                    .iter()
+                    .any(|tier| tier.contains(&variant))
+            })
+        })
+    };
+    for (variant, colour) in catalog.colour_variants() {
+        if built_in_layer(variant).is_some() {
+            return Err(Error::Validation(format!(
+                "{catalog_file:?}: colour variant {variant} is already a built-in variant"
+            )));
+        }
+        if built_in_layer(&colour.base).is_none() {
+            return Err(Error::Validation(format!(
+                "{catalog_file:?}: colour variant {variant} is recoloured from {:?}, which is not a built-in variant",
+                colour.base
+            )));
+        }
+    }
+    for (layer, link) in catalog.links() {
+        let position = |name: &str| {
+            get_parts_order()
+                .iter()
+                .position(|part| part.layer_name() == name)
+        };
+        let (Some(linked), Some(followed)) = (position(layer), position(&link.follows)) else {
+            return Err(Error::Validation(format!(
+                "{catalog_file:?}: link of layer {layer:?} to {:?} names an unknown layer",
+                link.follows
+            )));
+        };
+        // generation and the statistics derive linked layers from the
+        // anchor variant, sampled first
+        if link.follows != ANCHOR_LAYER || followed >= linked {
+            return Err(Error::Validation(format!(
+                "{catalog_file:?}: layer {layer} can only follow {ANCHOR_LAYER}, drawn before it"
+            )));
+        }
+    }
+
+    for gender in [Gender::Boy, Gender::Girl] {
+        let mut names = HashSet::new();
+        for set in catalog.sets(gender) {
+            if set.name.is_empty() || !names.insert(set.name.as_str()) {
+                return Err(Error::Validation(format!(
+                    "{catalog_file:?}: {gender} set name {:?} is empty or given twice",
+                    set.name
+                )));
+            }
+            if set.layers.is_empty() {
+                return Err(Error::Validation(format!(
+                    "{catalog_file:?}: {gender} set {:?} has no layers",
+                    set.name
+                )));
+            }
+            for (layer, variant) in &set.layers {
+                let Some(part) = layer_named(layer) else {
+                    return Err(Error::Validation(format!(
+                        "{catalog_file:?}: {gender} set {:?} has unknown layer {layer:?}",
+                        set.name
+                    )));
+                };
+                if let Some(follows) = Link::follows(layer) {
+                    return Err(Error::Validation(format!(
+                        "{catalog_file:?}: {gender} set {:?} forces layer {layer}, which follows {follows}",
+                        set.name
+                    )));
+                }
+                if !part
+                    .tiers(gender)
+                    .iter()
+                    .any(|tier| tier.contains(&variant.as_str()))
+                {
+                    return Err(Error::Validation(format!(
+                        "{catalog_file:?}: {gender} set {:?} uses {variant:?}, which is not a {gender} variant of layer {layer}",
+                        set.name
+                    )));
+                }
+            }
+        }
+        // the catalog is installed, so the model lists its sets
+        stats_model(gender).check_sets().map_err(|message| {
+            Error::Validation(format!("{catalog_file:?}: {gender} {message}"))
+        })?;
+    }
+    for (variant, _) in catalog.procedural_backgrounds() {
+        if background::Kind::of(variant).is_none() {
+            return Err(Error::Validation(format!(
+                "{catalog_file:?}: {variant:?} is not a procedural background, expected one of {:?}",
+                background::Kind::VARIANTS
+            )));
+        }
+    }
+    for (variant, placement) in catalog.placements() {
+        if layer_of_part(variant).is_none() {
+            return Err(Error::Validation(format!(
+                "{catalog_file:?}: placement of unknown variant {variant:?}"
+            )));
+        }
+        let known = match placement {
+            Placement::ZIndex(z) => *z <= get_parts_order().len(),
+            Placement::Above(layer) | Placement::Below(layer) => get_parts_order()
+                .iter()
+                .any(|part| part.layer_name() == layer),
+        };
+        if !known {
+            return Err(Error::Validation(format!(
+                "{catalog_file:?}: {variant} is placed at {placement:?}, outside the layer order"
+            )));
+        }
+    }
+    for (variant, blend) in catalog.blends() {
+        if layer_of_part(variant).is_none() {
+            return Err(Error::Validation(format!(
+                "{catalog_file:?}: blend of unknown variant {variant:?}"
+            )));
+        }
+        if !(0.0..=1.0).contains(&blend.opacity) {
+            return Err(Error::Validation(format!(
+                "{catalog_file:?}: opacity {} of {variant} is not between 0 and 1",
+                blend.opacity
+            )));
+        }
+    }
+    Ok(())
+}
+
+// How `generate_random_metronion` samples each layer, for exact statistics.
+fn stats_model(gender: Gender) -> stats::Model {
+    let layers = get_parts_order()
+        .iter()
+        .map(|part| stats::Layer {
+            name: part.layer_name(),
+            tiers: part
+                .tiers(gender)
+                .iter()
+                .map(|tier| tier.to_vec())
+                .collect(),
+            sampling: match part {
+                _ if Link::follows(part.layer_name()).is_some() => {
+                    let layer = part.layer_name();
+                    stats::Sampling::Derived(Box::new(move |anchor| {
+                        Link::linked(layer, anchor).map(str::to_string)
+                    }))
+                }
+                Parts::FaceAcc(_) => stats::Sampling::If(Box::new(move |hair_long| {
+                    HairLong::allows_face_acc(gender, hair_long)
+                })),
+                Parts::HeadPhone(_) => stats::Sampling::If(Box::new(move |hair_long| {
+                    HairLong::is_with_headphone(gender, hair_long)
+                })),
+                _ if part.is_required() => stats::Sampling::Required,
+                _ => stats::Sampling::Optional,
+            },
+        })
+        .collect();
+
+    let sets = catalog::get()
+        .sets(gender)
+        .iter()
+        .map(|set| stats::Set {
+            name: &set.name,
+            tier: set.rarity as usize,
+            layers: set
+                .layers
+                .iter()
+                .map(|(layer, variant)| (layer.as_str(), variant.as_str()))
+                .collect(),
+        })
+        .collect();
+
+    stats::Model {
+        tier_names: Rarity::ALL.iter().map(Rarity::name).collect(),
+        tier_probabilities: Rarity::ALL.iter().map(Rarity::probability).collect(),
+        anchor: ANCHOR_LAYER,
+        layers,
+        sets,
+    }
+}
+
+// Every layer with all its variants, in drawing order.
+fn rule_layers(gender: Gender) -> Vec<rules::Layer> {
+    get_parts_order()
+        .iter()
+        .map(|part| rules::Layer {
+            name: part.layer_name(),
+            variants: part.tiers(gender).concat(),
+            required: part.is_required(),
+            derived: Link::follows(part.layer_name()).is_some(),
+        })
+        .collect()
 }
 
 fn get_parts_order() -> [Parts; 9] {
@@ -741,106 +2076,933 @@ fn get_parts_order() -> [Parts; 9] {
     ]
 }
 
-fn is_boy(dir_path: &PathBuf) -> bool {
-    let dir_str = dir_path.to_str().unwrap();
-    dir_str.contains("NFT_B")
+// Sort the (layer, variant) pairs of a metronion into the order they are
+// drawn and recorded in the mapping: the layer order, unless the catalog file
+// places a variant elsewhere.
+fn stack(layers: Layers) -> Result<Layers> {
+    stack_with(catalog::get(), layers)
 }
 
-async fn handle(
-    input_dir: &PathBuf,
-    output_dir: &PathBuf,
-    gender: Gender,
-    total: usize,
-    is_reset: bool,
-) {
-    std::fs::create_dir_all(output_dir).expect("Failed to create output directory");
+fn stack_with(catalog: &catalog::Catalog, layers: Layers) -> Result<Layers> {
+    let order = get_parts_order();
+    let position = |layer: &str| {
+        order
+            .iter()
+            .position(|part| part.layer_name() == layer)
+            .ok_or_else(|| Error::Catalog(format!("unknown layer {layer}")))
+    };
 
-    let mapping = (1..=total)
+    let mut stacked = layers
         .into_iter()
-        .map(|i| {
-            let metronion_parts = generate_random_metronion(gender);
-            info!("Metronion {i:?} with parts {:?}", metronion_parts);
-            metronion_parts
+        .map(|(layer, variant)| {
+            let z = match catalog.placement(&variant) {
+                None => (position(layer)?, 0),
+                Some(Placement::ZIndex(z)) => (*z, -1),
+                Some(Placement::Above(other)) => (position(other)?, 1),
+                Some(Placement::Below(other)) => (position(other)?, -1),
+            };
+            Ok((z, (layer, variant)))
+        })
+        .collect::<Result<Vec<((usize, i8), _)>>>()?;
+    stacked.sort_by_key(|(z, _)| *z);
+
+    Ok(stacked.into_iter().map(|(_, layer)| layer).collect())
+}
+
+fn layer_named(name: &str) -> Option<Parts> {
+    get_parts_order()
+        .into_iter()
+        .find(|part| part.layer_name() == name)
+}
+
+// The layer of either gender listing the part.
+fn layer_of_part(part: &str) -> Option<Parts> {
+    get_parts_order().into_iter().find(|layer| {
+        [Gender::Boy, Gender::Girl]
+            .into_iter()
+            .any(|gender| layer.tiers(gender).iter().any(|tier| tier.contains(&part)))
+    })
+}
+
+// Rarity of every variant of the catalog of `gender`.
+fn rarities(gender: Gender) -> HashMap<&'static str, Rarity> {
+    get_parts_order()
+        .iter()
+        .flat_map(|part| Rarity::ALL.into_iter().zip(part.tiers(gender)))
+        .flat_map(|(rarity, variants)| variants.iter().map(move |&variant| (variant, rarity)))
+        .collect()
+}
+
+// A metronion is as rare as its rarest variant or its set.
+fn token_rarity(
+    rarity_of: &HashMap<&'static str, Rarity>,
+    parts: &[String],
+    set: Option<&catalog::Set>,
+) -> Rarity {
+    parts
+        .iter()
+        .filter_map(|part| rarity_of.get(part.as_str()).copied())
+        .chain(set.map(|set| set.rarity))
+        .max()
+        .unwrap_or(Rarity::Common)
+}
+
+// The outfit set recorded in the metadata of metronion `id` when it was
+// rolled. Metadata is only read when the catalog has sets.
+fn recorded_set(
+    output_dir: &Path,
+    gender: Gender,
+    id: usize,
+) -> Result<Option<&'static catalog::Set>> {
+    let sets = catalog::get().sets(gender);
+    if sets.is_empty() {
+        return Ok(None);
+    }
+
+    let metadata = metadata::read(output_dir, id)?;
+    let Some(name) = metadata.attribute(metadata::SET_TRAIT) else {
+        return Ok(None);
+    };
+    sets.iter()
+        .find(|set| set.name == name)
+        .map(Some)
+        .ok_or_else(|| {
+            Error::Validation(format!(
+                "metronion {id} wears set {name:?} which is not in the catalog"
+            ))
         })
-        .collect::<Vec<Vec<String>>>();
-
-    let tasks = mapping.iter().enumerate().map(|(i, metronion_parts)| {
-        tokio::spawn({
-            let input_dir = input_dir.clone();
-            let output_dir = output_dir.clone();
-            let metronion_parts = metronion_parts.clone();
-            async move { magick_metronion(i, metronion_parts, input_dir, output_dir) }
+}
+
+// The image a part is drawn from, recoloured for a colour variant.
+fn source_of(part: &str) -> (String, Option<Recolour>) {
+    match catalog::get().colour_variant(part) {
+        Some(colour) => (colour.base.clone(), Some(colour.recolour.clone())),
+        None => (part.to_string(), None),
+    }
+}
+
+// How every part of the stack of metronion `index` is drawn, from the
+// images in `input_dir`. A procedural background is drawn into `output_dir`
+// at the size of the other layers.
+fn layer_images(
+    input_dir: &Path,
+    output_dir: &Path,
+    index: usize,
+    parts: &[String],
+) -> std::result::Result<Vec<LayerImage>, String> {
+    let image_of = |part: &str| input_dir.join(format!("{}.png", source_of(part).0));
+    parts
+        .iter()
+        .map(|part| {
+            let path = match background::Kind::of(part) {
+                Some(kind) => {
+                    let template = parts
+                        .iter()
+                        .find(|other| background::Kind::of(other).is_none())
+                        .ok_or_else(|| format!("no layer image to size background {part}"))?;
+                    let size = render::image_size(&image_of(template))?;
+                    background::render(kind, index + 1, size, output_dir)?
+                }
+                None => image_of(part),
+            };
+            Ok(LayerImage {
+                path,
+                recolour: source_of(part).1,
+                blend: catalog::get().blend(part),
+            })
         })
-    });
+        .collect()
+}
+
+fn is_boy(dir_path: &Path) -> bool {
+    let dir_str = dir_path.to_string_lossy();
+    dir_str.contains("NFT_B")
+}
+
+// What to do when the mapping of a previous run already exists.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MappingMode {
+    Refuse,
+    Overwrite,
+    AppendContinueIds,
+}
+
+// Metronions that new ones must differ from, with the variants of their
+// significant layers when the rules set a minimum distance.
+#[derive(Default, Clone)]
+struct Taken {
+    parts: HashSet<Vec<String>>,
+    signatures: Vec<Vec<Option<String>>>,
+}
+
+impl Taken {
+    fn len(&self) -> usize {
+        self.parts.len()
+    }
+
+    // The parts in a fixed order, as placements may change the drawing order.
+    fn key(parts: &[String]) -> Vec<String> {
+        let mut key = parts.to_vec();
+        key.sort_unstable();
+        key
+    }
+}
+
+// A metronion to add to the collection, with the outfit set it was rolled
+// with.
+#[derive(Debug, Clone)]
+struct Token {
+    parts: Vec<String>,
+    set: Option<&'static catalog::Set>,
+}
+
+// Settings shared by the commands adding metronions to a collection.
+struct Generator {
+    gender: Gender,
+    input_dir: PathBuf,
+    output_dir: PathBuf,
+    pinned: BTreeMap<usize, PinnedToken>,
+    rules: Rules,
+    // layer of every variant, to compare existing metronions
+    layer_of: HashMap<&'static str, &'static str>,
+    rarity_of: HashMap<&'static str, Rarity>,
+    block_size: Option<usize>,
+    // exact variant counts, the ones not given follow the probabilities
+    quotas: Option<solver::Quotas>,
+    derivatives: Vec<derivatives::Profile>,
+    jobs: usize,
+}
+
+// Metronions taken while the solver hands out the quotas.
+struct Accepting<'a> {
+    generator: &'a Generator,
+    taken: Taken,
+}
+
+impl solver::Accepted for Accepting<'_> {
+    fn conflict(&self, token: &[(&'static str, String)]) -> Option<String> {
+        self.generator.conflict(token, &self.taken)
+    }
+
+    fn accept(&mut self, token: &[(&'static str, String)]) {
+        let parts = token.iter().map(|(_, part)| part.clone()).collect();
+        self.generator.take(&mut self.taken, parts);
+    }
+
+    fn remove(&mut self, token: &[(&'static str, String)]) {
+        let parts = token
+            .iter()
+            .map(|(_, part)| part.clone())
+            .collect::<Vec<String>>();
+        self.taken.parts.remove(&Taken::key(&parts));
+        if self.generator.rules.min_distance() > 1 {
+            let signature = self.generator.rules.signature(token);
+            if let Some(position) = self
+                .taken
+                .signatures
+                .iter()
+                .position(|other| *other == signature)
+            {
+                self.taken.signatures.swap_remove(position);
+            }
+        }
+    }
+}
+
+impl Generator {
+    fn new(input_dir: &Path, output_dir: &Path, args: &ArgMatches, jobs: usize) -> Result<Self> {
+        let gender = if is_boy(input_dir) {
+            Gender::Boy
+        } else {
+            Gender::Girl
+        };
+
+        let layers = rule_layers(gender);
+        let rules = match args.get_one::<PathBuf>("rules") {
+            Some(rules_file) => {
+                let rules = Rules::load(rules_file)?.validate(rules_file, &layers)?;
+                info!(
+                    "Load {} banned combinations and a minimum distance of {} layers from {rules_file:?}",
+                    rules.banned_count(),
+                    rules.min_distance()
+                );
+                rules
+            }
+            None => Rules::default(),
+        };
+        let layer_of: HashMap<&'static str, &'static str> = layers
+            .iter()
+            .flat_map(|layer| layer.variants.iter().map(|variant| (*variant, layer.name)))
+            .collect();
+        let pinned = match args.get_one::<PathBuf>("pinned") {
+            Some(pinned_file) => {
+                let layer_order = get_parts_order().map(|part| part.layer_name());
+                let pinned = pinned::load(
+                    pinned_file,
+                    &layer_order,
+                    |layer, part| layer_of.get(part) == Some(&layer),
+                    input_dir,
+                    |part| {
+                        background::Kind::of(part)
+                            .is_none()
+                            .then(|| source_of(part).0)
+                    },
+                )?;
+                validate_pinned(pinned_file, &pinned, &rules, |part| {
+                    layer_of.get(part).copied()
+                })?;
+                info!(
+                    "Load {} pinned metronions from {pinned_file:?}",
+                    pinned.len()
+                );
+                pinned
+            }
+            None => BTreeMap::new(),
+        };
+
+        let rarity_of = rarities(gender);
+
+        let block_size = args.get_one::<usize>("block-size").copied();
+        if block_size == Some(0) {
+            return Err(Error::Validation(
+                "--block-size must be at least 1".to_string(),
+            ));
+        }
+
+        let quotas = match args.get_one::<PathBuf>("quotas") {
+            Some(quotas_file) => {
+                let quotas = solver::load_quotas(quotas_file)?;
+                info!(
+                    "Load quotas of {} layers from {quotas_file:?}",
+                    quotas.len()
+                );
+                Some(quotas)
+            }
+            None if args.get_flag("exact-quotas") => Some(solver::Quotas::new()),
+            None => None,
+        };
+
+        let derivatives = match args.get_one::<PathBuf>("derivatives") {
+            Some(profiles_file) => {
+                let profiles = derivatives::load(profiles_file)?;
+                info!(
+                    "Load {} derivative profiles from {profiles_file:?}",
+                    profiles.len()
+                );
+                profiles
+            }
+            None => vec![],
+        };
+
+        let mut generator = Generator {
+            gender,
+            input_dir: input_dir.to_path_buf(),
+            output_dir: output_dir.to_path_buf(),
+            pinned,
+            rules,
+            layer_of,
+            rarity_of,
+            block_size,
+            quotas,
+            derivatives,
+            jobs,
+        };
+        for id in generator.pinned.keys().copied().collect::<Vec<usize>>() {
+            if let Pin::Parts(parts) = &generator.pinned[&id].pin {
+                let parts = generator.stacked(parts)?;
+                generator.pinned.get_mut(&id).expect("Pinned id").pin = Pin::Parts(parts);
+            }
+        }
+
+        Ok(generator)
+    }
+
+    fn checkpoint_exists(&self) -> bool {
+        Checkpoint::<Vec<String>>::path(&self.output_dir).exists()
+    }
+
+    fn taken(&self, mapping: impl IntoIterator<Item = Vec<String>>) -> Taken {
+        let mut taken = Taken::default();
+        for parts in mapping {
+            self.take(&mut taken, parts);
+        }
+        taken
+    }
+
+    fn take(&self, taken: &mut Taken, parts: Vec<String>) {
+        if self.rules.min_distance() > 1 {
+            let layers = parts
+                .iter()
+                .filter_map(|part| {
+                    self.layer_of
+                        .get(part.as_str())
+                        .map(|layer| (*layer, part.as_str()))
+                })
+                .collect::<Vec<(&str, &str)>>();
+            taken.signatures.push(self.rules.signature(&layers));
+        }
+        taken.parts.insert(Taken::key(&parts));
+    }
+
+    // `count` metronions with ids following `last_id`. Pinned ones take
+    // their place in the sequence, the others are sampled distinct from
+    // `taken` and from the pinned layer combinations.
+    fn generate(&self, last_id: usize, count: usize, taken: &mut Taken) -> Result<Vec<Token>> {
+        let ids = last_id + 1..=last_id + count;
+        for id in self.pinned.keys().filter(|id| !ids.contains(id)) {
+            if *id > last_id {
+                warn!("Pinned metronion {id} is beyond the generated ids and skipped");
+            }
+        }
+
+        let pinned = self.pinned.range(ids.clone()).collect::<Vec<_>>();
+        for (_, token) in &pinned {
+            if let Pin::Parts(parts) = &token.pin {
+                self.take(taken, parts.clone());
+            }
+        }
+
+        let sampled_count = count - pinned.len();
+        if let Some(capacity) = self.rules.capacity() {
+            let total = (taken.len() + sampled_count) as u128;
+            if total > capacity {
+                return Err(Error::Validation(format!(
+                    "at most {capacity} metronions can differ in {} significant layers, {total} requested",
+                    self.rules.min_distance()
+                )));
+            }
+        }
+
+        let mut sampled = match &self.quotas {
+            Some(quotas) => {
+                let pinned = pinned
+                    .iter()
+                    .map(|(_, token)| match &token.pin {
+                        Pin::Parts(parts) => Some(self.layers(parts)),
+                        Pin::Image(_) => None,
+                    })
+                    .collect::<Vec<_>>();
+                self.solve(quotas, sampled_count, &pinned, taken)?
+            }
+            None => self.generate_unique(sampled_count, taken)?,
+        };
+        if let Some(block_size) = self.block_size {
+            let slots = ids
+                .clone()
+                .filter(|id| !self.pinned.contains_key(id))
+                .collect::<Vec<usize>>();
+            let tiered = sampled
+                .into_iter()
+                .map(|token| (self.rarity(&token), token))
+                .collect();
+            sampled = stratify::stratify(&slots, block_size, tiered);
+        }
+
+        let mut sampled = sampled.into_iter();
+        Ok(ids
+            .map(|id| match self.pinned.get(&id).map(|token| &token.pin) {
+                Some(Pin::Parts(parts)) => Token {
+                    parts: parts.clone(),
+                    set: None,
+                },
+                Some(Pin::Image(_)) => Token {
+                    parts: vec![],
+                    set: None,
+                },
+                None => sampled.next().expect("Missing sampled metronion"),
+            })
+            .collect())
+    }
+
+    // Sample `count` metronions differing from each other and from `taken`,
+    // adding them to `taken`.
+    fn generate_unique(&self, count: usize, taken: &mut Taken) -> Result<Vec<Token>> {
+        let progress = Progress::new("generate", count);
+        let mut mapping = Vec::with_capacity(count);
+        for _ in 0..count {
+            let token = self.generate_distinct(taken)?;
+            self.take(taken, token.parts.clone());
+            mapping.push(token);
+            progress.inc(0);
+        }
+        progress.finish();
+
+        Ok(mapping)
+    }
+
+    // Assign the exact quotas to `count` sampled metronions next to the
+    // `pinned` ones, `None` for a pinned image, adding them to `taken`.
+    fn solve(
+        &self,
+        quotas: &solver::Quotas,
+        count: usize,
+        pinned: &[Option<Layers>],
+        taken: &mut Taken,
+    ) -> Result<Vec<Token>> {
+        let model = stats_model(self.gender);
+        let solver = solver::Solver::new(&model, quotas, count, pinned, self.rules.banned())?;
+        let mapping = solver
+            .solve(count, || Accepting {
+                generator: self,
+                taken: taken.clone(),
+            })?
+            .iter()
+            .map(|(set, parts)| {
+                Ok(Token {
+                    parts: self.stacked(parts)?,
+                    // the model lists the sets of the catalog in order
+                    set: set.map(|set| &catalog::get().sets(self.gender)[set]),
+                })
+            })
+            .collect::<Result<Vec<Token>>>()?;
+        info!("Assign the exact quotas to {count} metronions");
+
+        for token in &mapping {
+            self.take(taken, token.parts.clone());
+        }
+        Ok(mapping)
+    }
+
+    // Sample a metronion following the rules and differing from `taken`.
+    fn generate_distinct(&self, taken: &Taken) -> Result<Token> {
+        for _ in 0..MAX_ENSURE_ATTEMPTS {
+            let (set, layers) = generate_random_metronion(self.gender)?;
+            if self.conflict(&layers, taken).is_none() {
+                return Ok(Token {
+                    parts: layers.into_iter().map(|(_, part)| part).collect(),
+                    set,
+                });
+            }
+        }
+
+        if self.rules.min_distance() > 1 {
+            return Err(Error::Validation(format!(
+                "no metronion differs in {} significant layers from the {} taken ones after {MAX_ENSURE_ATTEMPTS} attempts, lower min_distance or the count",
+                self.rules.min_distance(),
+                taken.len()
+            )));
+        }
+        Err(Error::Catalog(format!(
+            "no metronion following the rules and distinct from the {} taken ones after {MAX_ENSURE_ATTEMPTS} attempts",
+            taken.len()
+        )))
+    }
+
+    // Why the metronion cannot join `taken`, if it cannot.
+    fn conflict(&self, layers: &[(&'static str, String)], taken: &Taken) -> Option<String> {
+        if self.rules.is_banned(layers) {
+            return Some("has a banned combination".to_string());
+        }
+        if self.rules.min_distance() > 1 {
+            let signature = self.rules.signature(layers);
+            if !taken
+                .signatures
+                .iter()
+                .all(|other| self.rules.is_far_from(&signature, other))
+            {
+                return Some(format!(
+                    "differs in fewer than {} significant layers from another metronion",
+                    self.rules.min_distance()
+                ));
+            }
+        }
+
+        let parts = layers
+            .iter()
+            .map(|(_, part)| part.clone())
+            .collect::<Vec<String>>();
+        if taken.parts.contains(&Taken::key(&parts)) {
+            return Some("duplicates another metronion".to_string());
+        }
+        None
+    }
+
+    // The parts of a metronion in drawing order.
+    fn stacked(&self, parts: &[String]) -> Result<Vec<String>> {
+        Ok(stack(self.layers(parts))?
+            .into_iter()
+            .map(|(_, part)| part)
+            .collect())
+    }
+
+    // The parts of a metronion as (layer, variant) pairs.
+    fn layers(&self, parts: &[String]) -> Vec<(&'static str, String)> {
+        parts
+            .iter()
+            .filter_map(|part| {
+                self.layer_of
+                    .get(part.as_str())
+                    .map(|layer| (*layer, part.clone()))
+            })
+            .collect()
+    }
+
+    fn rarity(&self, token: &Token) -> Rarity {
+        token_rarity(&self.rarity_of, &token.parts, token.set)
+    }
+
+    // Log and save how many metronions of each rarity every block holds.
+    fn report_blocks(&self, last_id: usize, mapping: &[Token]) -> Result<()> {
+        let Some(block_size) = self.block_size else {
+            return Ok(());
+        };
+
+        let blocks = stratify::block_counts(
+            block_size,
+            mapping
+                .iter()
+                .enumerate()
+                .filter(|(i, _)| !self.pinned.contains_key(&(last_id + i + 1)))
+                .map(|(i, token)| (last_id + i + 1, self.rarity(token))),
+        );
+        for block in &blocks {
+            info!(
+                "Metronions {}-{}: {:?}",
+                block.first_id, block.last_id, block.counts
+            );
+        }
+
+        let blocks_file = self.output_dir.join(stratify::BLOCKS_FILE);
+        files::write_json_atomic(&blocks_file, &blocks)?;
+        info!("Write rarity per block to {blocks_file:?}");
+        Ok(())
+    }
+
+    fn metadata(&self, id: usize, token: &Token) -> Metadata {
+        let traits = self
+            .layers(&token.parts)
+            .into_iter()
+            // a linked layer is drawn as part of the trait of the layer it
+            // follows
+            .filter(|(layer, _)| Link::follows(layer).is_none())
+            .filter_map(|(layer, part)| Some((layer_named(layer)?.trait_type(), part)))
+            .collect();
+        let mut metadata = Metadata::new(id, traits);
+        if let Some(set) = token.set {
+            metadata = metadata.with_attribute(metadata::SET_TRAIT, &set.name);
+        }
+        if token
+            .parts
+            .iter()
+            .any(|part| background::Kind::of(part).is_some())
+        {
+            metadata =
+                metadata.with_attribute(background::PALETTE_TRAIT, background::palette_name(id));
+        }
+        for profile in &self.derivatives {
+            metadata = metadata.with_derivative(&profile.name, profile.image(id - 1));
+        }
+        match self.pinned.get(&id) {
+            Some(token) => metadata.with_attribute(pinned::PINNED_TRAIT, &token.label),
+            None => metadata,
+        }
+    }
+}
+
+async fn handle(generator: &Generator, total: usize, mode: MappingMode) -> Result<()> {
+    let output_dir = &generator.output_dir;
+    let mapping_filepath = PathBuf::from(format!("mapping_{}.txt", generator.gender));
+    let stamp = files::backup_stamp();
+
+    if mode != MappingMode::Overwrite && generator.checkpoint_exists() {
+        return Err(Error::Validation(format!(
+            "{output_dir:?} holds an interrupted run, use --resume to finish it or --overwrite to start over"
+        )));
+    }
+
+    let existing = match mode {
+        MappingMode::Refuse => {
+            if mapping_filepath.exists() || !files::is_empty_dir(output_dir)? {
+                return Err(Error::Validation(format!(
+                    "{mapping_filepath:?} or {output_dir:?} already exists, use --overwrite or --append-continue-ids"
+                )));
+            }
+            vec![]
+        }
+        MappingMode::Overwrite => {
+            if mapping_filepath.exists() {
+                let backup = files::backup(&mapping_filepath, &stamp)?;
+                info!("Back up mapping file to {backup:?}");
+            }
+            if !files::is_empty_dir(output_dir)? {
+                let backup = files::backup(output_dir, &stamp)?;
+                info!("Back up output directory to {backup:?}");
+            }
+            vec![]
+        }
+        MappingMode::AppendContinueIds if mapping_filepath.exists() => {
+            let existing = mapping::read(&mapping_filepath)?;
+            let backup = files::backup_copy(&mapping_filepath, &stamp)?;
+            info!("Back up mapping file to {backup:?}");
+            existing
+        }
+        MappingMode::AppendContinueIds => vec![],
+    };
+    let last_id = existing.iter().map(|(id, _)| *id).max().unwrap_or(0);
+    let mut taken = generator.taken(existing.into_iter().map(|(_, parts)| parts));
+
+    let mapping = generator.generate(last_id, total, &mut taken)?;
     info!("Number of metronions = {:?}", mapping.len());
 
-    // write mapping to file
-    let mapping_filepath = PathBuf::from(format!("mapping_{}.txt", gender));
-    if is_reset {
-        fs_extra::file::remove(mapping_filepath.clone()).expect("Failed to reset mapping file");
+    add_metronions(generator, &mapping_filepath, last_id, mapping).await
+}
+
+// Grow an existing collection by `count` metronions distinct from the ones
+// already minted, rendering only the new ones.
+async fn extend(
+    generator: &Generator,
+    mapping_file: &Path,
+    count: usize,
+    max_supply: usize,
+) -> Result<()> {
+    if generator.checkpoint_exists() {
+        return Err(Error::Validation(format!(
+            "{:?} holds an interrupted run, use --resume to finish it first",
+            generator.output_dir
+        )));
     }
-    let mut file = OpenOptions::new()
-        .append(true)
-        .create(true)
-        .open(mapping_filepath.clone())
-        .expect("Failed to open the file.");
-    for (i, item) in mapping.iter().enumerate() {
-        writeln!(file, "{},{:?}", i + 1, item).expect("Failed to write");
+
+    let existing = mapping::read(mapping_file)?;
+    let remaining = max_supply.saturating_sub(existing.len());
+    if count > remaining {
+        return Err(Error::Validation(format!(
+            "{mapping_file:?} holds {} metronions, only {remaining} more fit in the supply of {max_supply}",
+            existing.len()
+        )));
     }
-    info!("Write metronion mappings to file {mapping_filepath:?}");
 
-    let mut stream = futures::stream::iter(tasks).buffered(BUFFER_SIZE);
+    let backup = files::backup_copy(mapping_file, &files::backup_stamp())?;
+    info!("Back up mapping file to {backup:?}");
+
+    let last_id = existing.iter().map(|(id, _)| *id).max().unwrap_or(0);
+    let mut taken = generator.taken(existing.into_iter().map(|(_, parts)| parts));
+    info!("Continue after metronion {last_id}");
+
+    let mapping = generator.generate(last_id, count, &mut taken)?;
+    info!("Number of new metronions = {:?}", mapping.len());
 
-    while let Some(result) = stream.next().await {}
+    add_metronions(generator, mapping_file, last_id, mapping).await
 }
 
-async fn generate_emotions(
-    mapping_file: &PathBuf,
-    input_dir: &PathBuf,
-    output_dir: &PathBuf,
-    from_index: usize,
-) {
-    let file = File::open(mapping_file).expect("Failed to open the mapping file.");
-    let reader = BufReader::new(file);
+#[derive(Debug, Serialize)]
+struct RerollEntry {
+    token_id: usize,
+    old: Vec<String>,
+    new: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    set: Option<&'static str>,
+}
+
+// Resample the given metronions under the same rules, keeping them distinct
+// from the whole collection including their previous combination, and
+// update their mapping line, metadata and image in place.
+async fn reroll(generator: &Generator, mapping_file: &Path, mut ids: Vec<usize>) -> Result<()> {
+    let output_dir = &generator.output_dir;
+    if generator.checkpoint_exists() {
+        return Err(Error::Validation(format!(
+            "{output_dir:?} holds an interrupted run, use --resume to finish it first"
+        )));
+    }
+
+    if generator.quotas.is_some() {
+        warn!("Quotas only apply to whole runs, rerolled metronions are sampled");
+    }
+
+    ids.sort_unstable();
+    ids.dedup();
+
+    let mut mapping = mapping::read(mapping_file)?;
+    if let Some(id) = ids
+        .iter()
+        .find(|id| !mapping.iter().any(|(mapped, _)| mapped == *id))
+    {
+        return Err(Error::Validation(format!(
+            "metronion {id} is not in {mapping_file:?}"
+        )));
+    }
+    if let Some(id) = ids.iter().find(|id| generator.pinned.contains_key(id)) {
+        return Err(Error::Validation(format!(
+            "metronion {id} is pinned and cannot be rerolled"
+        )));
+    }
+
+    let mut taken = generator.taken(mapping.iter().map(|(_, parts)| parts.clone()));
+    let mut entries = vec![];
+    let mut rerolled = vec![];
+    for (id, parts) in mapping.iter_mut().filter(|(id, _)| ids.contains(id)) {
+        let token = generator.generate_distinct(&taken)?;
+        generator.take(&mut taken, token.parts.clone());
+        info!("Reroll metronion {id}: {parts:?} -> {:?}", token.parts);
+
+        entries.push(RerollEntry {
+            token_id: *id,
+            old: std::mem::replace(parts, token.parts.clone()),
+            new: token.parts.clone(),
+            set: token.set.map(|set| set.name.as_str()),
+        });
+        rerolled.push((*id, token));
+    }
+
+    let stamp = files::backup_stamp();
+    let backup = files::backup_copy(mapping_file, &stamp)?;
+    info!("Back up mapping file to {backup:?}");
+    mapping::write(mapping_file, &mapping)?;
+    info!("Write metronion mappings to file {mapping_file:?}");
+
+    std::fs::create_dir_all(output_dir).with_path(output_dir)?;
+    let audit_file = output_dir.join(format!("reroll-{stamp}.json"));
+    files::write_json_atomic(&audit_file, &entries)?;
+    info!("Write reroll audit log to {audit_file:?}");
+
+    metadata::write_all(
+        output_dir,
+        rerolled
+            .iter()
+            .map(|(id, token)| (*id, generator.metadata(*id, token))),
+    )?;
+
+    let checkpoint = Checkpoint {
+        input_dir: generator.input_dir.clone(),
+        mapping_file: mapping_file.to_path_buf(),
+        pending: entries
+            .into_iter()
+            .map(|entry| (entry.token_id - 1, entry.new))
+            .collect(),
+        derivatives: generator.derivatives.clone(),
+        catalog_file: catalog::get().file().map(Path::to_path_buf),
+    };
+    render_metronions(output_dir, checkpoint, generator.jobs).await
+}
+
+// Append metronions to the mapping file with ids following `last_id`, then
+// write their metadata and render them. Pinned images are copied as they are.
+async fn add_metronions(
+    generator: &Generator,
+    mapping_file: &Path,
+    last_id: usize,
+    mapping: Vec<Token>,
+) -> Result<()> {
+    let output_dir = &generator.output_dir;
+    std::fs::create_dir_all(output_dir).with_path(output_dir)?;
+
+    mapping::append(
+        mapping_file,
+        mapping
+            .iter()
+            .enumerate()
+            .map(|(i, token)| (last_id + i + 1, token.parts.as_slice())),
+    )?;
+    info!("Write metronion mappings to file {mapping_file:?}");
+
+    generator.report_blocks(last_id, &mapping)?;
+
+    let written = metadata::write_all(
+        output_dir,
+        mapping.iter().enumerate().map(|(i, token)| {
+            let id = last_id + i + 1;
+            (id, generator.metadata(id, token))
+        }),
+    )?;
+    info!(
+        "Write {written} metadata files to {:?}",
+        output_dir.join(metadata::METADATA_DIR)
+    );
+
+    let mut pending = BTreeMap::new();
+    for (i, token) in mapping.into_iter().enumerate() {
+        let index = last_id + i;
+        match generator.pinned.get(&(index + 1)).map(|token| &token.pin) {
+            Some(Pin::Image(image)) => {
+                let output_file = output_dir.join(format!("{index}.png"));
+                files::copy_atomic(image, &output_file)?;
+                info!("Copy pinned image {image:?} of metronion {}", index + 1);
+                derivatives::render(&generator.derivatives, &output_file, output_dir, index)
+                    .map_err(|message| Error::Render {
+                        token_id: index + 1,
+                        message,
+                    })?;
+            }
+            _ => {
+                pending.insert(index, token.parts);
+            }
+        }
+    }
 
-    info!("From index {from_index:?}");
+    let checkpoint = Checkpoint {
+        input_dir: generator.input_dir.clone(),
+        mapping_file: mapping_file.to_path_buf(),
+        pending,
+        derivatives: generator.derivatives.clone(),
+        catalog_file: catalog::get().file().map(Path::to_path_buf),
+    };
+    render_metronions(output_dir, checkpoint, generator.jobs).await
+}
 
-    let metronion_parts = reader
-        .lines()
+// Continue an interrupted run from the checkpoint in its output directory.
+async fn resume(output_dir: &Path, jobs: usize) -> Result<()> {
+    let checkpoint = Checkpoint::load(output_dir)?;
+    let removed = files::remove_partials(output_dir)?;
+    info!(
+        "Resume {} metronions from {:?}, removed {removed} partial files",
+        checkpoint.pending.len(),
+        checkpoint.mapping_file
+    );
+
+    render_metronions(output_dir, checkpoint, jobs).await
+}
+
+async fn render_metronions(
+    output_dir: &Path,
+    checkpoint: Checkpoint<Vec<String>>,
+    jobs: usize,
+) -> Result<()> {
+    let input_dir = checkpoint.input_dir.clone();
+    let render_dir = output_dir.to_path_buf();
+    let profiles = checkpoint.derivatives.clone();
+
+    render::render_checkpointed("render", output_dir, checkpoint, jobs, move |i, parts| {
+        magick_metronion(i, parts, input_dir.clone(), render_dir.clone(), &profiles)
+    })
+    .await
+}
+
+// Layer stacks of every emotion for each metronion in the mapping file,
+// keyed by the metronion index.
+fn emotion_stacks(
+    mapping_file: &Path,
+    from_index: usize,
+) -> Result<Vec<(usize, Vec<Vec<String>>)>> {
+    let metronion_parts = mapping::read(mapping_file)?
         .into_iter()
-        .filter_map(|line| {
-            let mut line = line.unwrap();
-            line = line
-                .replace(" ", "")
-                .replace("\"", "")
-                .replace("[", "")
-                .replace("]", "");
-
-            let mut line = line
-                .split(",")
-                .map(|item| item.to_string())
-                .collect::<Vec<String>>();
-
-            line.remove(0);
-            // remove Face and FaceAcc
+        .map(|(_, mut line)| {
+            // the emotions are drawn in place of Face, without FaceAcc
+            let face = line
+                .iter()
+                .position(|item| matches!(layer_of_part(item), Some(Parts::Face(_))))
+                .map(|face| {
+                    line[..face]
+                        .iter()
+                        .filter(|item| !item.contains("Face"))
+                        .count()
+                });
             line.retain(|item| !item.contains("Face"));
-            Some(line)
+            (face, line)
         })
-        .collect::<Vec<Vec<String>>>();
+        .collect::<Vec<(Option<usize>, Vec<String>)>>();
 
     let extended_metronion_parts = metronion_parts
         .iter()
         .enumerate()
-        .filter_map(|(index, item)| {
+        .filter_map(|(index, (face, item))| {
             if index < from_index + 1 {
                 return None;
             }
             // add emotions
             let mut result: Vec<Vec<String>> = vec![];
             for i in 1..=10 {
-                if let Some(index) = item.iter().position(|item| item.contains("Clothes")) {
+                if let Some(index) = face {
                     let mut res = item.clone();
-                    res.insert(index + 1, format!("NFT_Emo_{i}"));
+                    res.insert(*index, format!("NFT_Emo_{i}"));
                     result.push(res);
                 }
             }
@@ -848,158 +3010,297 @@ async fn generate_emotions(
         })
         .collect::<Vec<(usize, Vec<Vec<String>>)>>();
 
-    // for metronion in metronion_parts {
-    //     info!("{:?}", metronion);
-    // }
-
-    let tasks = extended_metronion_parts
-        .into_iter()
-        .map(|(i, metronion_parts)| {
-            tokio::spawn({
-                let input_dir = input_dir.clone();
-                let output_dir = output_dir.clone();
-                let metronion_parts = metronion_parts.clone();
-                async move {
-                    for parts in metronion_parts {
-                        magick_emotions(i, parts, input_dir.clone(), output_dir.clone());
-                    }
-                }
-            })
-        });
-    info!("Number of metronions = {:?}", metronion_parts.len());
-
-    let mut stream = futures::stream::iter(tasks).buffered(BUFFER_SIZE);
-
-    while let Some(result) = stream.next().await {}
+    Ok(extended_metronion_parts)
 }
 
-fn split_at_first<'a>(input: &'a str, pattern: &'a str) -> (&'a str, &'a str) {
-    if let Some(pos) = input.find(pattern) {
-        let (first, second) = input.split_at(pos);
-        let second = &second[pattern.len()..];
-        (first, second)
+async fn generate_emotions(
+    mapping_file: &Path,
+    input_dir: &Path,
+    output_dir: &Path,
+    from_index: usize,
+    resume: bool,
+    jobs: usize,
+) -> Result<()> {
+    std::fs::create_dir_all(output_dir).with_path(output_dir)?;
+
+    let checkpoint = if resume {
+        let checkpoint = Checkpoint::load(output_dir)?;
+        let removed = files::remove_partials(output_dir)?;
+        info!("Resume emotions, removed {removed} partial files");
+        checkpoint
     } else {
-        (input, "")
-    }
+        info!("From index {from_index:?}");
+        Checkpoint {
+            input_dir: input_dir.to_path_buf(),
+            mapping_file: mapping_file.to_path_buf(),
+            pending: emotion_stacks(mapping_file, from_index)?
+                .into_iter()
+                .collect(),
+            derivatives: vec![],
+            catalog_file: catalog::get().file().map(Path::to_path_buf),
+        }
+    };
+    info!("Number of metronions = {:?}", checkpoint.pending.len());
+
+    let input_dir = checkpoint.input_dir.clone();
+    let render_dir = output_dir.to_path_buf();
+
+    render::render_checkpointed(
+        "emotions",
+        output_dir,
+        checkpoint,
+        jobs,
+        move |i, metronion_parts: Vec<Vec<String>>| {
+            metronion_parts
+                .into_iter()
+                .flat_map(|parts| magick_emotions(i, parts, input_dir.clone(), render_dir.clone()))
+                .collect()
+        },
+    )
+    .await
 }
 
-fn magick_metronion(index: usize, parts: Vec<String>, input_dir: PathBuf, output_dir: PathBuf) {
+fn magick_metronion(
+    index: usize,
+    parts: Vec<String>,
+    input_dir: PathBuf,
+    output_dir: PathBuf,
+    profiles: &[derivatives::Profile],
+) -> Vec<RenderFailure> {
     let output_file = output_dir.join(format!("{index:}.png"));
-    let output_file_path = output_file.to_str().unwrap();
-
-    let inputs_path = parts
-        .into_iter()
-        .map(|part| {
-            let p = input_dir.join(format!("{part:}.png"));
-            p.to_str().unwrap().to_string()
-        })
-        .collect::<Vec<String>>();
 
-    let output = std::process::Command::new("magick")
-        .args(&["convert"])
-        .args(inputs_path)
-        .args(&["-background", "none", "-flatten", output_file_path])
-        .output()
-        .expect("Failed to execute command magic k");
-
-    if output.status.success() {
-        info!("Generate metronion {index:?} successfully!");
-    } else {
-        error!("Command failed with exit code: {}", output.status);
+    match render::with_retries(index + 1, &parts, || {
+        let inputs = layer_images(&input_dir, &output_dir, index, &parts)?;
+        render::magick_compose(&inputs, &output_file)?;
+        derivatives::render(profiles, &output_file, &output_dir, index)
+    }) {
+        Ok(()) => vec![],
+        Err(failure) => vec![failure],
     }
 }
 
-fn magick_emotions(index: usize, parts: Vec<String>, input_dir: PathBuf, output_dir: PathBuf) {
+fn magick_emotions(
+    index: usize,
+    parts: Vec<String>,
+    input_dir: PathBuf,
+    output_dir: PathBuf,
+) -> Vec<RenderFailure> {
+    let emotions_dir = output_dir.clone();
     let output_dir = output_dir.join(format!("{index:}"));
-    std::fs::create_dir_all(&output_dir).expect("Failed to create output directory");
 
-    let emo_part_index = parts
+    let emo_part = parts
         .iter()
-        .position(|item| item.contains("NFT_Emo"))
-        .unwrap();
-    let emo_part = &parts[emo_part_index].to_owned();
+        .find(|item| item.contains("NFT_Emo"))
+        .cloned()
+        .unwrap_or_default();
 
     let output_file = output_dir.join(format!("{index:}_{emo_part:}.png"));
-    let output_file_path = output_file.to_str().unwrap();
-
-    let inputs_path = parts
-        .into_iter()
-        .map(|part| {
-            let p = input_dir.join(format!("{part:}.png"));
-            p.to_str().unwrap().to_string()
-        })
-        .collect::<Vec<String>>();
-
-    let output = std::process::Command::new("magick")
-        .args(&["convert"])
-        .args(inputs_path)
-        .args(&["-background", "none", "-flatten", output_file_path])
-        .output()
-        .expect("Failed to execute command magic k");
 
-    if output.status.success() {
-        info!(
-            "Generate metronion {index:?} with emotions {:?} successfully!",
-            emo_part
-        );
-    } else {
-        error!("Command failed with exit code: {}", output.status);
+    match render::with_retries(index + 1, &parts, || {
+        std::fs::create_dir_all(&output_dir).map_err(|err| format!("{output_dir:?}: {err}"))?;
+        let inputs = layer_images(&input_dir, &emotions_dir, index, &parts)?;
+        render::magick_compose(&inputs, &output_file)
+    }) {
+        Ok(()) => vec![],
+        Err(failure) => vec![failure],
     }
 }
 
-fn generate_random_metronion(gender: Gender) -> Vec<String> {
+// The (layer, variant) pairs of a metronion.
+type Layers = Vec<(&'static str, String)>;
+
+// Sample the variant of every layer, as (layer, variant) pairs in drawing
+// order, along with the outfit set forcing some of them.
+fn generate_random_metronion(gender: Gender) -> Result<(Option<&'static catalog::Set>, Layers)> {
     let mut hair_long_part_str: Option<&str> = None;
-    let mut metronion_parts: Vec<String> = vec![];
+    let mut metronion_parts: Vec<(&'static str, String)> = vec![];
+    let set = random_set(gender);
 
     for part in get_parts_order() {
-        let part_str = match part {
-            Parts::Background(_) => ensure_part(|| Background::random_part(gender)),
-            Parts::Hand(_) => ensure_part(|| Hand::random_part(gender)),
-            Parts::HairLong(_) => {
-                hair_long_part_str = ensure_part(|| HairLong::random_part(gender));
+        let layer = part.layer_name();
+        let forced = set.and_then(|set| set.variant(layer));
+        let part_str = match (&part, Link::follows(layer)) {
+            (Parts::HairLong(_), _) => {
+                hair_long_part_str = match forced {
+                    Some(variant) => Some(variant),
+                    None => ensure_part("HairLong", || part.random_part(gender))?,
+                };
                 hair_long_part_str
             }
-            Parts::Body(_) => ensure_part(|| Body::random_part(gender)),
-            Parts::Clothes(_) => ensure_part(|| Clothes::random_part(gender)),
-            Parts::Face(_) => ensure_part(|| Face::random_part(gender)),
-            Parts::FaceAcc(_) => {
-                if matches!(gender, Gender::Boy)
-                    && hair_long_part_str.is_some()
-                    && !HairLong::is_with_face_acc(hair_long_part_str.unwrap())
-                {
-                    None
-                } else {
-                    FaceAcc::random_part(gender)
-                }
-            }
-            Parts::Hair(_) => Hair::from_hair_long(hair_long_part_str),
-            Parts::HeadPhone(_) => {
-                if hair_long_part_str.is_some()
-                    && !HairLong::is_with_headphone(gender, hair_long_part_str.unwrap())
-                {
-                    None
-                } else {
-                    HeadPhone::random_part(gender)
-                }
-            }
+            _ if forced.is_some() => forced,
+            (_, Some(follows)) => match metronion_parts.iter().find(|(other, _)| *other == follows)
+            {
+                Some((_, followed)) => Some(Link::linked(layer, followed).ok_or_else(|| {
+                    Error::Catalog(format!("{follows} {followed} has no linked {layer}"))
+                })?),
+                None => None,
+            },
+            (Parts::FaceAcc(_), _) => match hair_long_part_str {
+                Some(hair_long) if !HairLong::allows_face_acc(gender, hair_long) => None,
+                _ => part.random_part(gender),
+            },
+            (Parts::HeadPhone(_), _) => match hair_long_part_str {
+                Some(hair_long) if !HairLong::is_with_headphone(gender, hair_long) => None,
+                _ => part.random_part(gender),
+            },
+            _ if part.is_required() => ensure_part(layer, || part.random_part(gender))?,
+            _ => part.random_part(gender),
         };
 
         if let Some(part_str) = part_str {
-            metronion_parts.push(part_str.to_string());
+            metronion_parts.push((layer, part_str.to_string()));
         }
     }
 
-    metronion_parts
+    Ok((set, stack(metronion_parts)?))
 }
 
+// Give up on a layer that never yields a variant instead of spinning forever.
+const MAX_ENSURE_ATTEMPTS: usize = 10_000;
+
 // always return Some(str)
-fn ensure_part<F>(f: F) -> Option<&'static str>
+fn ensure_part<F>(layer: &str, f: F) -> Result<Option<&'static str>>
 where
     F: Fn() -> Option<&'static str>,
 {
-    loop {
+    for _ in 0..MAX_ENSURE_ATTEMPTS {
         if let Some(result) = f() {
-            return Some(result);
+            return Ok(Some(result));
+        }
+    }
+
+    Err(Error::Catalog(format!(
+        "layer {layer} has no variant for any rarity"
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layers(pairs: &[(&'static str, &str)]) -> Layers {
+        pairs
+            .iter()
+            .map(|(layer, variant)| (*layer, variant.to_string()))
+            .collect()
+    }
+
+    fn catalog(json: &str) -> catalog::Catalog {
+        serde_json::from_str(json).expect("Invalid catalog")
+    }
+
+    #[test]
+    fn stack_follows_the_layer_order() {
+        let stacked = stack_with(
+            &catalog::Catalog::default(),
+            layers(&[
+                ("Hair", "NFT_B_Hair_1"),
+                ("Body", "NFT_Body_1"),
+                ("FaceAcc", "NFT_Face_Acc_7"),
+                ("Background", "NFT_BG_1"),
+            ]),
+        )
+        .expect("Known layers");
+
+        assert_eq!(
+            stacked,
+            layers(&[
+                ("Background", "NFT_BG_1"),
+                ("Body", "NFT_Body_1"),
+                ("FaceAcc", "NFT_Face_Acc_7"),
+                ("Hair", "NFT_B_Hair_1"),
+            ])
+        );
+    }
+
+    #[test]
+    fn stack_moves_placed_variants() {
+        let catalog = catalog(
+            r#"{ "placements": {
+                "NFT_Face_Acc_7": { "above": "Hair" },
+                "NFT_Hand_13": { "z_index": 5 },
+                "NFT_BG_1": { "below": "Background" }
+            } }"#,
+        );
+        let stacked = stack_with(
+            &catalog,
+            layers(&[
+                ("Background", "NFT_BG_1"),
+                ("Hand", "NFT_Hand_13"),
+                ("Body", "NFT_Body_1"),
+                ("Face", "NFT_B_Face_1"),
+                ("FaceAcc", "NFT_Face_Acc_7"),
+                ("Hair", "NFT_B_Hair_1"),
+            ]),
+        )
+        .expect("Known layers");
+
+        // the hand goes before the face at position 5, the accessory over
+        // the hair
+        assert_eq!(
+            stacked,
+            layers(&[
+                ("Background", "NFT_BG_1"),
+                ("Body", "NFT_Body_1"),
+                ("Hand", "NFT_Hand_13"),
+                ("Face", "NFT_B_Face_1"),
+                ("Hair", "NFT_B_Hair_1"),
+                ("FaceAcc", "NFT_Face_Acc_7"),
+            ])
+        );
+    }
+
+    #[test]
+    fn stack_rejects_a_placement_next_to_an_unknown_layer() {
+        let catalog = catalog(r#"{ "placements": { "NFT_Face_Acc_7": { "above": "Hat" } } }"#);
+        let result = stack_with(&catalog, layers(&[("FaceAcc", "NFT_Face_Acc_7")]));
+
+        assert!(matches!(result, Err(Error::Catalog(message)) if message.contains("Hat")));
+    }
+    fn pinned(parts: &[&str]) -> BTreeMap<usize, PinnedToken> {
+        BTreeMap::from([(
+            5,
+            PinnedToken {
+                pin: Pin::Parts(parts.iter().map(|part| part.to_string()).collect()),
+                label: "Reserved".to_string(),
+            },
+        )])
+    }
+
+    fn validate(parts: &[&str], rules: &Rules) -> Result<()> {
+        validate_pinned(Path::new("pinned.json"), &pinned(parts), rules, |part| {
+            layer_of_part(part).map(|layer| layer.layer_name())
+        })
+    }
+
+    #[test]
+    fn validate_pinned_needs_the_linked_variant() {
+        let rules = Rules::default();
+
+        assert!(validate(&["NFT_B_Hair_Long_11", "NFT_B_Hair_11"], &rules).is_ok());
+        for parts in [
+            &["NFT_B_Hair_Long_11", "NFT_B_Hair_12"][..],
+            &["NFT_B_Hair_Long_11"],
+            &["NFT_B_Hair_11"],
+        ] {
+            assert!(
+                matches!(validate(parts, &rules), Err(Error::Validation(_))),
+                "{parts:?} should be rejected"
+            );
         }
     }
+
+    #[test]
+    fn validate_pinned_rejects_a_banned_combination() {
+        let rules: Rules = serde_json::from_str(
+            r#"{ "banned": [{ "Hand": "NFT_Hand_2", "Body": "NFT_Body_1" }] }"#,
+        )
+        .expect("Valid rules");
+
+        assert!(validate(&["NFT_Hand_1", "NFT_Body_1"], &rules).is_ok());
+        assert!(matches!(
+            validate(&["NFT_Hand_2", "NFT_Body_1"], &rules),
+            Err(Error::Validation(message)) if message.contains("banned")
+        ));
+    }
 }
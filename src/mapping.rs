@@ -0,0 +1,200 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+
+use crate::error::{Error, IoContext, Result};
+use crate::files;
+
+// A mapping file has one metronion per line, written as
+// `<id>,["<part>", "<part>", ...]` in drawing order.
+pub fn read(mapping_file: &Path) -> Result<Vec<(usize, Vec<String>)>> {
+    let file = File::open(mapping_file).with_path(mapping_file)?;
+    let reader = BufReader::new(file);
+
+    let mut mapping = vec![];
+    for (line_index, line) in reader.lines().enumerate() {
+        let line = line.with_path(mapping_file)?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let entry = parse_line(&line).map_err(|message| Error::Parse {
+            path: mapping_file.to_path_buf(),
+            line: line_index + 1,
+            message,
+        })?;
+        mapping.push(entry);
+    }
+
+    Ok(mapping)
+}
+
+fn parse_line(line: &str) -> std::result::Result<(usize, Vec<String>), String> {
+    let (id, parts) = line
+        .split_once(',')
+        .ok_or_else(|| format!("expected `<id>,[<parts>]`, got {line:?}"))?;
+
+    let id = id
+        .trim()
+        .parse::<usize>()
+        .map_err(|err| format!("invalid metronion id {id:?}: {err}"))?;
+
+    let parts = parts
+        .trim()
+        .strip_prefix('[')
+        .and_then(|parts| parts.strip_suffix(']'))
+        .ok_or_else(|| format!("expected a bracketed part list, got {parts:?}"))?;
+
+    let parts = parts
+        .split(',')
+        .map(|item| item.trim().replace('"', ""))
+        .filter(|item| !item.is_empty())
+        .collect::<Vec<String>>();
+
+    Ok((id, parts))
+}
+
+fn write_line(file: &mut impl Write, id: usize, parts: &[String]) -> std::io::Result<()> {
+    writeln!(file, "{},{:?}", id, parts)
+}
+
+// Replace the whole mapping file in one go so an interruption never leaves
+// it half written.
+pub fn write(mapping_file: &Path, mapping: &[(usize, Vec<String>)]) -> Result<()> {
+    write_after(
+        mapping_file,
+        vec![],
+        mapping.iter().map(|(id, parts)| (*id, parts.as_slice())),
+    )
+}
+
+// Add metronions at the end of the mapping file, replacing it like `write`.
+pub fn append<'a>(
+    mapping_file: &Path,
+    mapping: impl IntoIterator<Item = (usize, &'a [String])>,
+) -> Result<()> {
+    let contents = if mapping_file.exists() {
+        std::fs::read(mapping_file).with_path(mapping_file)?
+    } else {
+        vec![]
+    };
+    write_after(mapping_file, contents, mapping)
+}
+
+fn write_after<'a>(
+    mapping_file: &Path,
+    mut contents: Vec<u8>,
+    mapping: impl IntoIterator<Item = (usize, &'a [String])>,
+) -> Result<()> {
+    for (id, parts) in mapping {
+        write_line(&mut contents, id, parts).with_path(mapping_file)?;
+    }
+    files::write_atomic(mapping_file, &contents)
+}
+
+// A token id list has ids separated by commas or new lines, `#` starts a
+// comment.
+pub fn read_ids(ids_file: &Path) -> Result<Vec<usize>> {
+    let contents = std::fs::read_to_string(ids_file).with_path(ids_file)?;
+
+    let mut ids = vec![];
+    for (line_index, line) in contents.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default();
+        for id in line.split(',').map(str::trim).filter(|id| !id.is_empty()) {
+            let id = id.parse::<usize>().map_err(|err| Error::Parse {
+                path: ids_file.to_path_buf(),
+                line: line_index + 1,
+                message: format!("invalid metronion id {id:?}: {err}"),
+            })?;
+            ids.push(id);
+        }
+    }
+
+    Ok(ids)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parts(parts: &[&str]) -> Vec<String> {
+        parts.iter().map(|part| part.to_string()).collect()
+    }
+
+    #[test]
+    fn parse_line_reads_the_id_and_parts() {
+        assert_eq!(
+            parse_line(r#"3,["NFT_BG_1", "NFT_Body_2"]"#),
+            Ok((3, parts(&["NFT_BG_1", "NFT_Body_2"])))
+        );
+        // a pinned image has no parts
+        assert_eq!(parse_line("2,[]"), Ok((2, vec![])));
+    }
+
+    #[test]
+    fn parse_line_rejects_malformed_lines() {
+        for line in [
+            r#"["NFT_BG_1"]"#,
+            r#"three,["NFT_BG_1"]"#,
+            r#"-1,["NFT_BG_1"]"#,
+            r#"3,"NFT_BG_1""#,
+            r#"3,["NFT_BG_1""#,
+        ] {
+            assert!(parse_line(line).is_err(), "{line} should be rejected");
+        }
+    }
+
+    #[test]
+    fn read_skips_blank_lines_and_reports_the_malformed_one() {
+        let dir = files::test_dir("mapping-read");
+        let mapping_file = dir.join("mapping_boy.txt");
+        std::fs::write(&mapping_file, "1,[\"NFT_BG_1\"]\n\n2,[]\n").expect("Test mapping");
+        assert_eq!(
+            read(&mapping_file).expect("Valid mapping"),
+            [(1, parts(&["NFT_BG_1"])), (2, vec![])]
+        );
+
+        std::fs::write(&mapping_file, "1,[\"NFT_BG_1\"]\n\nmetronion 2\n").expect("Test mapping");
+        assert!(matches!(
+            read(&mapping_file),
+            Err(Error::Parse { line: 3, .. })
+        ));
+    }
+    #[test]
+    fn append_adds_to_the_written_mapping() {
+        let dir = files::test_dir("mapping-append");
+        let mapping_file = dir.join("mapping_boy.txt");
+        write(&mapping_file, &[(1, parts(&["NFT_BG_1"]))]).expect("Written mapping");
+        append(&mapping_file, [(2, [].as_slice())]).expect("Appended mapping");
+
+        assert_eq!(
+            read(&mapping_file).expect("Valid mapping"),
+            [(1, parts(&["NFT_BG_1"])), (2, vec![])]
+        );
+    }
+
+    #[test]
+    fn read_ids_takes_commas_new_lines_and_comments() {
+        let dir = files::test_dir("mapping-ids");
+        let ids_file = dir.join("ids.txt");
+        std::fs::write(
+            &ids_file,
+            "# rejected by the artists\n4, 9\n\n12 # too dark\n",
+        )
+        .expect("Test ids");
+
+        assert_eq!(read_ids(&ids_file).expect("Valid ids"), [4, 9, 12]);
+    }
+
+    #[test]
+    fn read_ids_reports_the_line_of_an_invalid_id() {
+        let dir = files::test_dir("mapping-invalid-ids");
+        let ids_file = dir.join("ids.txt");
+        std::fs::write(&ids_file, "4\n9 12\n").expect("Test ids");
+
+        assert!(matches!(
+            read_ids(&ids_file),
+            Err(Error::Parse { line: 2, .. })
+        ));
+    }
+}
@@ -0,0 +1,103 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use crate::error::{Error, IoContext, Result};
+use crate::files;
+
+pub const METADATA_DIR: &str = "metadata";
+// Trait naming the outfit set a metronion wears in full.
+pub const SET_TRAIT: &str = "Set";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Attribute {
+    pub trait_type: String,
+    pub value: String,
+}
+
+// Marketplace metadata of one metronion, written to
+// `<output>/metadata/<id>.json` next to its image.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Metadata {
+    pub name: String,
+    pub image: String,
+    pub attributes: Vec<Attribute>,
+    // resized copies of the image keyed by profile name
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub derivatives: BTreeMap<String, String>,
+}
+
+impl Metadata {
+    // `traits` holds the (trait, variant) pairs of the metronion.
+    pub fn new(id: usize, traits: Vec<(&str, String)>) -> Self {
+        let attributes = traits
+            .into_iter()
+            .map(|(trait_type, value)| Attribute {
+                trait_type: trait_type.to_string(),
+                value,
+            })
+            .collect();
+
+        Metadata {
+            name: format!("Metronion #{id}"),
+            // images are numbered from 0 while ids start at 1
+            image: format!("{}.png", id - 1),
+            attributes,
+            derivatives: BTreeMap::new(),
+        }
+    }
+
+    pub fn with_attribute(mut self, trait_type: &str, value: &str) -> Self {
+        self.attributes.push(Attribute {
+            trait_type: trait_type.to_string(),
+            value: value.to_string(),
+        });
+        self
+    }
+
+    // Value of the first attribute of the trait, if any.
+    pub fn attribute(&self, trait_type: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|attribute| attribute.trait_type == trait_type)
+            .map(|attribute| attribute.value.as_str())
+    }
+
+    pub fn with_derivative(mut self, name: &str, image: String) -> Self {
+        self.derivatives.insert(name.to_string(), image);
+        self
+    }
+}
+
+pub fn path(output_dir: &Path, id: usize) -> PathBuf {
+    output_dir.join(METADATA_DIR).join(format!("{id}.json"))
+}
+
+pub fn read(output_dir: &Path, id: usize) -> Result<Metadata> {
+    let metadata_file = path(output_dir, id);
+    let contents = std::fs::read(&metadata_file).with_path(&metadata_file)?;
+    serde_json::from_slice(&contents).map_err(|err| Error::Parse {
+        path: metadata_file,
+        line: err.line(),
+        message: err.to_string(),
+    })
+}
+
+pub fn write(output_dir: &Path, id: usize, metadata: &Metadata) -> Result<()> {
+    let dir = output_dir.join(METADATA_DIR);
+    std::fs::create_dir_all(&dir).with_path(&dir)?;
+    files::write_json_atomic(&path(output_dir, id), metadata)
+}
+
+// Write the metadata of every given metronion, keyed by id.
+pub fn write_all(
+    output_dir: &Path,
+    tokens: impl IntoIterator<Item = (usize, Metadata)>,
+) -> Result<usize> {
+    let mut written = 0;
+    for (id, metadata) in tokens {
+        write(output_dir, id, &metadata)?;
+        written += 1;
+    }
+    Ok(written)
+}
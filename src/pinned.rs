@@ -0,0 +1,234 @@
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use crate::error::{Error, IoContext, Result};
+
+// Metadata trait flagging pinned metronions, valued with their label.
+pub const PINNED_TRAIT: &str = "Pinned";
+
+fn default_label() -> String {
+    "Reserved".to_string()
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Source {
+    // variant per layer name, e.g. `"Background": "NFT_BG_8"`
+    Layers(BTreeMap<String, String>),
+    // finished artwork, relative to the pinned file
+    Image(PathBuf),
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct Entry {
+    #[serde(flatten)]
+    source: Source,
+    #[serde(default = "default_label")]
+    label: String,
+}
+
+#[derive(Debug, Clone)]
+pub enum Pin {
+    Parts(Vec<String>),
+    Image(PathBuf),
+}
+
+#[derive(Debug, Clone)]
+pub struct PinnedToken {
+    pub pin: Pin,
+    pub label: String,
+}
+
+// Hand-crafted and reserved metronions keyed by id, loaded from a JSON file
+// such as
+//
+//   {
+//     "1": { "image": "one_of_ones/genesis.png", "label": "1/1" },
+//     "2": { "layers": { "Background": "NFT_BG_8", "Body": "NFT_Body_1" } }
+//   }
+//
+// Layer variants must be variants of their layer, as `is_variant` tells, and
+// are put in `layer_order`. The image each one is drawn from, given by
+// `source_of` unless it is generated, must exist in `input_dir`.
+pub fn load(
+    pinned_file: &Path,
+    layer_order: &[&str],
+    is_variant: impl Fn(&str, &str) -> bool,
+    input_dir: &Path,
+    source_of: impl Fn(&str) -> Option<String>,
+) -> Result<BTreeMap<usize, PinnedToken>> {
+    let contents = std::fs::read(pinned_file).with_path(pinned_file)?;
+    let entries: BTreeMap<usize, Entry> =
+        serde_json::from_slice(&contents).map_err(|err| Error::Parse {
+            path: pinned_file.to_path_buf(),
+            line: err.line(),
+            message: err.to_string(),
+        })?;
+    let base_dir = pinned_file.parent().unwrap_or(Path::new(""));
+
+    let mut pinned = BTreeMap::new();
+    for (id, entry) in entries {
+        if id == 0 {
+            return Err(Error::Validation(format!(
+                "{pinned_file:?}: metronion ids start at 1"
+            )));
+        }
+
+        let pin = match entry.source {
+            Source::Layers(layers) => {
+                if let Some(layer) = layers
+                    .keys()
+                    .find(|layer| !layer_order.contains(&layer.as_str()))
+                {
+                    return Err(Error::Validation(format!(
+                        "{pinned_file:?}: metronion {id} has unknown layer {layer:?}, expected one of {layer_order:?}"
+                    )));
+                }
+
+                if let Some((layer, part)) =
+                    layers.iter().find(|(layer, part)| !is_variant(layer, part))
+                {
+                    return Err(Error::Validation(format!(
+                        "{pinned_file:?}: metronion {id} uses {part:?}, which is not a variant of layer {layer}"
+                    )));
+                }
+
+                let parts = layer_order
+                    .iter()
+                    .filter_map(|layer| layers.get(*layer).cloned())
+                    .collect::<Vec<String>>();
+                if let Some(part) = parts.iter().find(|part| {
+                    source_of(part)
+                        .is_some_and(|source| !input_dir.join(format!("{source}.png")).exists())
+                }) {
+                    return Err(Error::Validation(format!(
+                        "{pinned_file:?}: metronion {id} uses {part:?} which is not in {input_dir:?}"
+                    )));
+                }
+                Pin::Parts(parts)
+            }
+            Source::Image(image) => {
+                let image = base_dir.join(image);
+                if !image.exists() {
+                    return Err(Error::Validation(format!(
+                        "{pinned_file:?}: image {image:?} of metronion {id} does not exist"
+                    )));
+                }
+                Pin::Image(image)
+            }
+        };
+
+        pinned.insert(
+            id,
+            PinnedToken {
+                pin,
+                label: entry.label,
+            },
+        );
+    }
+
+    Ok(pinned)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::files;
+
+    const LAYER_ORDER: [&str; 2] = ["Background", "Body"];
+
+    // A pinned file holding `json`, next to the art of NFT_BG_1 and
+    // NFT_Body_1.
+    fn pinned_file(name: &str, json: &str) -> PathBuf {
+        let dir = files::test_dir(&format!("pinned-{name}"));
+        for part in ["NFT_BG_1", "NFT_Body_1"] {
+            std::fs::write(dir.join(format!("{part}.png")), "").expect("Test art");
+        }
+        let pinned_file = dir.join("pinned.json");
+        std::fs::write(&pinned_file, json).expect("Test pinned file");
+        pinned_file
+    }
+
+    fn load_pinned(pinned_file: &Path) -> Result<BTreeMap<usize, PinnedToken>> {
+        load(
+            pinned_file,
+            &LAYER_ORDER,
+            |layer, part| {
+                matches!(
+                    (layer, part),
+                    ("Background", "NFT_BG_1" | "NFT_BG_2") | ("Body", "NFT_Body_1")
+                )
+            },
+            pinned_file.parent().expect("Test directory"),
+            |part| Some(part.to_string()),
+        )
+    }
+
+    #[test]
+    fn load_rejects_a_variant_of_another_layer() {
+        let pinned_file = pinned_file(
+            "variant-of-another-layer",
+            r#"{ "2": { "layers": { "Body": "NFT_BG_1" } } }"#,
+        );
+
+        assert!(matches!(
+            load_pinned(&pinned_file),
+            Err(Error::Validation(message)) if message.contains("\"NFT_BG_1\"")
+        ));
+    }
+    #[test]
+    fn load_puts_the_layers_in_order_and_the_images_next_to_the_file() {
+        let pinned_file = pinned_file(
+            "valid",
+            r#"{
+                "1": { "image": "NFT_BG_1.png", "label": "1/1" },
+                "2": { "layers": { "Body": "NFT_Body_1", "Background": "NFT_BG_1" } }
+            }"#,
+        );
+        let pinned = load_pinned(&pinned_file).expect("Valid pinned file");
+
+        let dir = pinned_file.parent().expect("Test directory");
+        assert!(matches!(&pinned[&1].pin, Pin::Image(image) if *image == dir.join("NFT_BG_1.png")));
+        assert_eq!(pinned[&1].label, "1/1");
+        assert!(
+            matches!(&pinned[&2].pin, Pin::Parts(parts) if parts == &["NFT_BG_1", "NFT_Body_1"])
+        );
+        assert_eq!(pinned[&2].label, "Reserved");
+    }
+
+    #[test]
+    fn load_rejects_invalid_pins() {
+        for (name, json, expected) in [
+            (
+                "id-0",
+                r#"{ "0": { "image": "NFT_BG_1.png" } }"#,
+                "ids start at 1",
+            ),
+            (
+                "unknown-layer",
+                r#"{ "2": { "layers": { "Hat": "NFT_Hat_1" } } }"#,
+                "unknown layer",
+            ),
+            (
+                "missing-art",
+                r#"{ "2": { "layers": { "Background": "NFT_BG_2" } } }"#,
+                "which is not in",
+            ),
+            (
+                "missing-image",
+                r#"{ "2": { "image": "one_of_ones/genesis.png" } }"#,
+                "does not exist",
+            ),
+        ] {
+            let pinned_file = pinned_file(name, json);
+            assert!(
+                matches!(
+                    load_pinned(&pinned_file),
+                    Err(Error::Validation(message)) if message.contains(expected)
+                ),
+                "{name} should be rejected"
+            );
+        }
+    }
+}
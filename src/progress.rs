@@ -0,0 +1,140 @@
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::log::{self, LogFormat};
+
+// Minimum time between two JSON progress events of a stage.
+const JSON_EVENT_INTERVAL: Duration = Duration::from_secs(1);
+
+// The bar currently drawn, so log lines can be printed above it.
+static ACTIVE: Mutex<Option<ProgressBar>> = Mutex::new(None);
+
+pub fn suspend<F: FnOnce()>(f: F) {
+    let bar = ACTIVE.lock().ok().and_then(|active| active.clone());
+    match bar {
+        Some(bar) => bar.suspend(f),
+        None => f(),
+    }
+}
+
+#[derive(Serialize)]
+struct ProgressEvent {
+    event: &'static str,
+    stage: &'static str,
+    done: u64,
+    total: u64,
+    failed: u64,
+    per_sec: f64,
+    eta_secs: Option<f64>,
+}
+
+// Progress of one stage (generation, rendering, emotions, ...), drawn as a
+// bar on a terminal, as JSON events with --log-format json, or not at all
+// with --quiet.
+pub struct Progress {
+    stage: &'static str,
+    total: u64,
+    done: AtomicU64,
+    failed: AtomicU64,
+    started: Instant,
+    bar: Option<ProgressBar>,
+    last_event: Mutex<Instant>,
+}
+
+impl Progress {
+    pub fn new(stage: &'static str, total: usize) -> Self {
+        let total = total as u64;
+        let bar = match log::format() {
+            LogFormat::Text if !log::is_quiet() => {
+                let bar = ProgressBar::with_draw_target(Some(total), ProgressDrawTarget::stderr());
+                bar.set_style(
+                    ProgressStyle::with_template(
+                        "{prefix:>9} [{bar:40.cyan/blue}] {pos}/{len} {per_sec} ETA {eta} {msg}",
+                    )
+                    .expect("Invalid progress template")
+                    .progress_chars("=> "),
+                );
+                bar.set_prefix(stage);
+                bar.set_message("failed 0");
+                if let Ok(mut active) = ACTIVE.lock() {
+                    *active = Some(bar.clone());
+                }
+                Some(bar)
+            }
+            _ => None,
+        };
+
+        Progress {
+            stage,
+            total,
+            done: AtomicU64::new(0),
+            failed: AtomicU64::new(0),
+            started: Instant::now(),
+            bar,
+            last_event: Mutex::new(Instant::now()),
+        }
+    }
+
+    // Record one finished token, of which `failed` images failed.
+    pub fn inc(&self, failed: usize) {
+        let done = self.done.fetch_add(1, Ordering::Relaxed) + 1;
+        let failed = self.failed.fetch_add(failed as u64, Ordering::Relaxed) + failed as u64;
+
+        if let Some(bar) = &self.bar {
+            bar.inc(1);
+            bar.set_message(format!("failed {failed}"));
+        } else if log::format() == LogFormat::Json && !log::is_quiet() {
+            let due = self
+                .last_event
+                .lock()
+                .map(|mut last_event| {
+                    let due = last_event.elapsed() >= JSON_EVENT_INTERVAL;
+                    if due {
+                        *last_event = Instant::now();
+                    }
+                    due
+                })
+                .unwrap_or(false);
+            if due {
+                self.emit(done, failed);
+            }
+        }
+    }
+
+    pub fn finish(&self) {
+        let done = self.done.load(Ordering::Relaxed);
+        let failed = self.failed.load(Ordering::Relaxed);
+
+        if let Some(bar) = &self.bar {
+            bar.finish();
+            if let Ok(mut active) = ACTIVE.lock() {
+                *active = None;
+            }
+        } else if log::format() == LogFormat::Json && !log::is_quiet() {
+            self.emit(done, failed);
+        }
+    }
+
+    fn emit(&self, done: u64, failed: u64) {
+        let elapsed = self.started.elapsed().as_secs_f64();
+        let per_sec = if elapsed > 0.0 {
+            done as f64 / elapsed
+        } else {
+            0.0
+        };
+        let eta_secs = (per_sec > 0.0).then(|| (self.total - done) as f64 / per_sec);
+
+        log::json_line(&ProgressEvent {
+            event: "progress",
+            stage: self.stage,
+            done,
+            total: self.total,
+            failed,
+            per_sec,
+            eta_secs,
+        });
+    }
+}
@@ -0,0 +1,519 @@
+use futures::stream::StreamExt;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::checkpoint::Checkpoint;
+use crate::error::{Error, Result};
+use crate::files;
+use crate::progress::Progress;
+use crate::shutdown;
+
+// Total number of times a single image is attempted before it is reported
+// as failed.
+pub const MAX_RENDER_ATTEMPTS: usize = 3;
+
+const RETRY_DELAY: Duration = Duration::from_millis(500);
+
+pub const FAILURES_FILE: &str = "failures.json";
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RenderFailure {
+    pub token_id: usize,
+    pub parts: Vec<String>,
+    pub attempts: usize,
+    pub stderr: String,
+}
+
+// Outcome of a batch of render tasks, by metronion index.
+#[derive(Debug, Default)]
+pub struct RenderReport {
+    pub done: Vec<usize>,
+    pub failures: Vec<RenderFailure>,
+}
+
+// Layers of a render task, listed in the failure report when it panics.
+pub trait LayerStack {
+    fn layers(&self) -> Vec<String>;
+}
+
+impl LayerStack for Vec<String> {
+    fn layers(&self) -> Vec<String> {
+        self.clone()
+    }
+}
+
+impl LayerStack for Vec<Vec<String>> {
+    fn layers(&self) -> Vec<String> {
+        self.concat()
+    }
+}
+
+// How a layer image is composed over the ones below it.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BlendMode {
+    Normal,
+    Multiply,
+    Screen,
+    Overlay,
+    Add,
+}
+
+impl BlendMode {
+    fn compose(&self) -> &'static str {
+        match self {
+            BlendMode::Normal => "Over",
+            BlendMode::Multiply => "Multiply",
+            BlendMode::Screen => "Screen",
+            BlendMode::Overlay => "Overlay",
+            BlendMode::Add => "Plus",
+        }
+    }
+}
+
+// Blend mode, opacity and offset in pixels of a layer image, so effects and
+// small sprites do not need full-canvas art.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Blend {
+    pub mode: BlendMode,
+    pub opacity: f64,
+    pub offset: (i32, i32),
+}
+
+impl Blend {
+    pub const NORMAL: Blend = Blend {
+        mode: BlendMode::Normal,
+        opacity: 1.0,
+        offset: (0, 0),
+    };
+}
+
+impl Default for Blend {
+    fn default() -> Self {
+        Blend::NORMAL
+    }
+}
+
+// Colour change of a layer image at render time, making a colour variant
+// out of another variant's art.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Recolour {
+    // hue rotation in degrees and saturation in percent of the source
+    Shift { hue: f64, saturation: f64 },
+    // exact colours replaced, as "#rrggbb" pairs of source and target
+    Palette(Vec<(String, String)>),
+}
+
+impl Recolour {
+    fn args(&self) -> Vec<OsString> {
+        match self {
+            Recolour::Shift { hue, saturation } => vec![
+                "-modulate".into(),
+                // magick turns the hue by 180 degrees every 100
+                format!("100,{saturation},{}", 100.0 + hue / 1.8).into(),
+            ],
+            Recolour::Palette(colours) => {
+                let mut args = vec!["-fuzz".into(), "2%".into()];
+                for (source, target) in colours {
+                    args.extend(["-fill", target, "-opaque", source].map(OsString::from));
+                }
+                args
+            }
+        }
+    }
+}
+
+// A layer image with how it is drawn.
+#[derive(Debug, Clone)]
+pub struct LayerImage {
+    pub path: PathBuf,
+    pub recolour: Option<Recolour>,
+    pub blend: Blend,
+}
+
+// Compose the layer images bottom up, each recoloured and with its blend,
+// into the output file, returning the command's stderr on failure.
+pub fn magick_compose(
+    inputs: &[LayerImage],
+    output_file: &Path,
+) -> std::result::Result<(), String> {
+    let mut args = vec![OsString::from("convert")];
+    args.extend(compose_args(inputs));
+    magick(args, output_file)
+}
+
+// Arguments of `magick convert` stacking the layer images into one, on a
+// transparent canvas the size of the first one. Plain layers are flattened
+// as before. Both leave the background transparent for later padding.
+pub fn compose_args(inputs: &[LayerImage]) -> Vec<OsString> {
+    let mut args = vec![];
+    if inputs
+        .iter()
+        .all(|input| input.recolour.is_none() && input.blend == Blend::NORMAL)
+    {
+        args.extend(inputs.iter().map(|input| input.path.as_os_str().to_owned()));
+        args.extend(["-background", "none", "-flatten"].map(OsString::from));
+        return args;
+    }
+
+    args.extend(["-background", "none"].map(OsString::from));
+    if let Some(canvas) = inputs.first() {
+        args.push(canvas.path.as_os_str().to_owned());
+        args.extend(["-alpha", "transparent"].map(OsString::from));
+    }
+    for LayerImage {
+        path,
+        recolour,
+        blend,
+    } in inputs
+    {
+        args.push("(".into());
+        args.push(path.as_os_str().to_owned());
+        if let Some(recolour) = recolour {
+            args.extend(recolour.args());
+        }
+        if blend.opacity < 1.0 {
+            args.extend(["-channel", "A", "-evaluate", "multiply"].map(OsString::from));
+            args.push(blend.opacity.to_string().into());
+            args.push("+channel".into());
+        }
+        args.push(")".into());
+        args.push("-geometry".into());
+        args.push(format!("{:+}{:+}", blend.offset.0, blend.offset.1).into());
+        args.push("-compose".into());
+        args.push(blend.mode.compose().into());
+        args.push("-composite".into());
+    }
+    args
+}
+
+// Run magick writing to a partial file, renamed to the output file once
+// complete.
+pub fn magick(args: Vec<OsString>, output_file: &Path) -> std::result::Result<(), String> {
+    let partial_file = files::partial_path(output_file);
+    let output = std::process::Command::new("magick")
+        .args(args)
+        .arg(&partial_file)
+        .output()
+        .map_err(|err| format!("Failed to execute command magick: {err}"))?;
+
+    if !output.status.success() {
+        let _ = std::fs::remove_file(&partial_file);
+        return Err(format!(
+            "Command failed with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    std::fs::rename(&partial_file, output_file).map_err(|err| format!("{output_file:?}: {err}"))
+}
+
+// Width and height of a PNG image, read from its header.
+pub fn image_size(image: &Path) -> std::result::Result<(u32, u32), String> {
+    let file = std::fs::File::open(image).map_err(|err| format!("{image:?}: {err}"))?;
+    let reader = png::Decoder::new(file)
+        .read_info()
+        .map_err(|err| format!("{image:?}: {err}"))?;
+    let info = reader.info();
+    Ok((info.width, info.height))
+}
+
+// Run `render` up to MAX_RENDER_ATTEMPTS times, backing off a little between
+// attempts since most failures are transient resource exhaustion.
+pub fn with_retries<F>(
+    token_id: usize,
+    parts: &[String],
+    render: F,
+) -> std::result::Result<(), RenderFailure>
+where
+    F: Fn() -> std::result::Result<(), String>,
+{
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+        match render() {
+            Ok(()) => return Ok(()),
+            Err(stderr) if attempts >= MAX_RENDER_ATTEMPTS => {
+                error!("Metronion {token_id:?} failed after {attempts} attempts: {stderr}");
+                return Err(RenderFailure {
+                    token_id,
+                    parts: parts.to_vec(),
+                    attempts,
+                    stderr,
+                });
+            }
+            Err(stderr) => {
+                warn!("Metronion {token_id:?} attempt {attempts} failed, retrying: {stderr}");
+                std::thread::sleep(RETRY_DELAY * attempts as u32);
+            }
+        }
+    }
+}
+
+// Turn a panicked or cancelled render task into a failure of its metronion.
+pub async fn join_render(
+    task: tokio::task::JoinHandle<Vec<RenderFailure>>,
+    index: usize,
+    parts: Vec<String>,
+) -> (usize, Vec<RenderFailure>) {
+    let failures = task.await.unwrap_or_else(|err| {
+        vec![RenderFailure {
+            token_id: index + 1,
+            parts,
+            attempts: 0,
+            stderr: err.to_string(),
+        }]
+    });
+    (index, failures)
+}
+
+// Number of parallel render jobs when --jobs is not given.
+pub fn default_jobs() -> usize {
+    std::thread::available_parallelism()
+        .map(|count| count.get())
+        .unwrap_or(1)
+}
+
+// Drive the render tasks with at most `jobs` of them in flight. `tasks` is
+// consumed lazily, so a task is only spawned once a slot frees up and memory
+// stays flat however many metronions there are. Once the run is interrupted
+// no new task is started.
+pub async fn collect<I, F>(mut tasks: I, jobs: usize, progress: &Progress) -> RenderReport
+where
+    I: Iterator<Item = F>,
+    F: std::future::Future<Output = (usize, Vec<RenderFailure>)>,
+{
+    let tasks = std::iter::from_fn(|| {
+        if shutdown::is_cancelled() {
+            None
+        } else {
+            tasks.next()
+        }
+    });
+
+    let mut report = RenderReport::default();
+    let mut stream = futures::stream::iter(tasks).buffer_unordered(jobs);
+
+    while let Some((index, failures)) = stream.next().await {
+        progress.inc(failures.len());
+        if failures.is_empty() {
+            report.done.push(index);
+        }
+        report.failures.extend(failures);
+    }
+    progress.finish();
+
+    report.done.sort();
+    report.failures.sort_by_key(|failure| failure.token_id);
+    report
+}
+
+// Render every pending item of the checkpoint with `render`, keeping the
+// checkpoint on disk until the run completes.
+pub async fn render_checkpointed<T, R>(
+    stage: &'static str,
+    output_dir: &Path,
+    mut checkpoint: Checkpoint<T>,
+    jobs: usize,
+    render: R,
+) -> Result<()>
+where
+    T: LayerStack + Clone + Serialize + DeserializeOwned + Send + 'static,
+    R: Fn(usize, T) -> Vec<RenderFailure> + Clone + Send + 'static,
+{
+    checkpoint.save(output_dir)?;
+
+    let progress = Progress::new(stage, checkpoint.pending.len());
+    let tasks = checkpoint.pending.iter().map(|(index, item)| {
+        let task = tokio::task::spawn_blocking({
+            let index = *index;
+            let item = item.clone();
+            let render = render.clone();
+            move || render(index, item)
+        });
+        join_render(task, *index, item.layers())
+    });
+    let report = collect(tasks, jobs, &progress).await;
+
+    for index in &report.done {
+        checkpoint.pending.remove(index);
+    }
+
+    if shutdown::is_cancelled() {
+        let checkpoint = checkpoint.save(output_dir)?;
+        files::write_json_atomic(&output_dir.join(FAILURES_FILE), &report.failures)?;
+        return Err(Error::Cancelled {
+            checkpoint: Some(checkpoint),
+        });
+    }
+
+    Checkpoint::<T>::remove(output_dir)?;
+    write_failures(output_dir, &report.failures)
+}
+
+pub fn write_failures(output_dir: &Path, failures: &[RenderFailure]) -> Result<()> {
+    let failures_file = output_dir.join(FAILURES_FILE);
+    files::write_json_atomic(&failures_file, failures)?;
+
+    if failures.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::RenderFailures {
+            count: failures.len(),
+            report: failures_file,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layer(path: &str, recolour: Option<Recolour>, blend: Blend) -> LayerImage {
+        LayerImage {
+            path: PathBuf::from(path),
+            recolour,
+            blend,
+        }
+    }
+
+    fn compose(inputs: &[LayerImage]) -> Vec<String> {
+        compose_args(inputs)
+            .into_iter()
+            .map(|arg| arg.to_string_lossy().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn compose_args_flatten_plain_layers() {
+        let inputs = [
+            layer("bg.png", None, Blend::NORMAL),
+            layer("body.png", None, Blend::NORMAL),
+        ];
+
+        assert_eq!(
+            compose(&inputs),
+            ["bg.png", "body.png", "-background", "none", "-flatten"]
+        );
+    }
+
+    #[test]
+    fn compose_args_composite_each_layer_with_its_blend() {
+        let glow = Blend {
+            mode: BlendMode::Add,
+            opacity: 0.5,
+            offset: (3, -4),
+        };
+        let inputs = [
+            layer("bg.png", None, Blend::NORMAL),
+            layer("glow.png", None, glow),
+        ];
+
+        assert_eq!(
+            compose(&inputs),
+            [
+                "-background",
+                "none",
+                "bg.png",
+                "-alpha",
+                "transparent",
+                "(",
+                "bg.png",
+                ")",
+                "-geometry",
+                "+0+0",
+                "-compose",
+                "Over",
+                "-composite",
+                "(",
+                "glow.png",
+                "-channel",
+                "A",
+                "-evaluate",
+                "multiply",
+                "0.5",
+                "+channel",
+                ")",
+                "-geometry",
+                "+3-4",
+                "-compose",
+                "Plus",
+                "-composite",
+            ]
+        );
+    }
+    #[test]
+    fn compose_args_recolour_only_their_layer() {
+        let inputs = [layer(
+            "hair.png",
+            Some(Recolour::Shift {
+                hue: -45.0,
+                saturation: 130.0,
+            }),
+            Blend::NORMAL,
+        )];
+
+        let args = compose(&inputs);
+        let hair = args
+            .iter()
+            .rposition(|arg| arg == "hair.png")
+            .expect("Hair layer");
+        // a quarter turn back is 25 below magick's neutral hue of 100
+        assert_eq!(args[hair + 1..hair + 4], ["-modulate", "100,130,75", ")"]);
+    }
+
+    #[test]
+    fn palette_recolour_replaces_each_colour() {
+        let recolour = Recolour::Palette(vec![
+            ("#ffffff".to_string(), "#1b1f3b".to_string()),
+            ("#d9d9d9".to_string(), "#2c325c".to_string()),
+        ]);
+
+        assert_eq!(
+            recolour.args(),
+            [
+                "-fuzz", "2%", "-fill", "#1b1f3b", "-opaque", "#ffffff", "-fill", "#2c325c",
+                "-opaque", "#d9d9d9",
+            ]
+        );
+    }
+    #[test]
+    fn with_retries_gets_past_a_transient_failure() {
+        let attempts = std::cell::Cell::new(0);
+        let result = with_retries(1, &[], || {
+            attempts.set(attempts.get() + 1);
+            match attempts.get() {
+                1 => Err("resource exhausted".to_string()),
+                _ => Ok(()),
+            }
+        });
+
+        assert!(result.is_ok());
+        assert_eq!(attempts.get(), 2);
+    }
+
+    #[test]
+    fn with_retries_reports_the_last_failure() {
+        let attempts = std::cell::Cell::new(0);
+        let parts = vec!["NFT_BG_1".to_string()];
+        let failure = with_retries(4, &parts, || {
+            attempts.set(attempts.get() + 1);
+            Err(format!("attempt {} failed", attempts.get()))
+        })
+        .expect_err("Every attempt fails");
+
+        assert_eq!(failure.token_id, 4);
+        assert_eq!(failure.parts, parts);
+        assert_eq!(failure.attempts, MAX_RENDER_ATTEMPTS);
+        assert_eq!(
+            failure.stderr,
+            format!("attempt {MAX_RENDER_ATTEMPTS} failed")
+        );
+    }
+}
@@ -0,0 +1,401 @@
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use crate::error::{Error, IoContext, Result};
+
+// A layer of the catalog as seen by the rules.
+pub struct Layer {
+    pub name: &'static str,
+    pub variants: Vec<&'static str>,
+    // every metronion has a variant of this layer
+    pub required: bool,
+    // the variant follows from another layer
+    pub derived: bool,
+}
+
+// Constraints on the generated metronions beyond the catalog, loaded from a
+// JSON file such as
+//
+//   {
+//     "banned": [
+//       { "Clothes": "NFT_B_Clothes_3", "HairLong": "NFT_B_Hair_Long_7" },
+//       { "Hand": "NFT_Hand_13" }
+//     ],
+//     "min_distance": 2,
+//     "significant_layers": ["Hand", "HairLong", "Clothes", "Face"]
+//   }
+//
+// A banned combination is a set of layer variants that no metronion may
+// have all at once. With a minimum distance, any two metronions differ in
+// at least that many of the significant layers, all the sampled ones by
+// default.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rules {
+    #[serde(default)]
+    banned: Vec<BTreeMap<String, String>>,
+    min_distance: Option<usize>,
+    significant_layers: Option<Vec<String>>,
+    // upper bound of the metronions meeting the minimum distance
+    #[serde(skip)]
+    capacity: Option<u128>,
+}
+
+impl Rules {
+    pub fn load(rules_file: &Path) -> Result<Self> {
+        let contents = std::fs::read(rules_file).with_path(rules_file)?;
+        serde_json::from_slice(&contents).map_err(|err| Error::Parse {
+            path: rules_file.to_path_buf(),
+            line: err.line(),
+            message: err.to_string(),
+        })
+    }
+
+    // Check the rules against the catalog of one gender, dropping the bans
+    // naming variants of the other gender as they can never apply.
+    pub fn validate(mut self, rules_file: &Path, layers: &[Layer]) -> Result<Self> {
+        for (index, ban) in self.banned.iter().enumerate() {
+            if ban.is_empty() {
+                return Err(Error::Validation(format!(
+                    "{rules_file:?}: banned combination {index} is empty and would ban everything"
+                )));
+            }
+            if let Some(layer) = ban
+                .keys()
+                .find(|name| !layers.iter().any(|layer| layer.name == name.as_str()))
+            {
+                return Err(Error::Validation(format!(
+                    "{rules_file:?}: banned combination {index} has unknown layer {layer:?}"
+                )));
+            }
+        }
+
+        self.banned.retain(|ban| {
+            let applies = ban.iter().all(|(name, variant)| {
+                layers
+                    .iter()
+                    .any(|layer| layer.name == name && layer.variants.contains(&variant.as_str()))
+            });
+            if !applies {
+                warn!("Banned combination {ban:?} names variants outside the catalog and never applies");
+            }
+            applies
+        });
+
+        let open = self.open_variants(layers);
+        let closed = layers
+            .iter()
+            .zip(&open)
+            .filter(|(_, open)| open.is_empty())
+            .map(|(layer, _)| layer.name)
+            .collect::<Vec<&str>>();
+        if !closed.is_empty() {
+            return Err(Error::Validation(format!(
+                "{rules_file:?}: the banned combinations leave no variant to pick in layers {}",
+                closed.join(", ")
+            )));
+        }
+
+        self.validate_distance(rules_file, layers)?;
+
+        Ok(self)
+    }
+
+    // The variants of each layer that some metronion can still have. A variant
+    // is ruled out when a ban is completed by it together with the variants
+    // every metronion is left with, or together with each remaining variant
+    // of another required layer. Ruling out variants can force others, so
+    // this repeats until nothing changes.
+    fn open_variants(&self, layers: &[Layer]) -> Vec<Vec<&'static str>> {
+        let mut open = layers
+            .iter()
+            .map(|layer| layer.variants.clone())
+            .collect::<Vec<Vec<&'static str>>>();
+
+        loop {
+            let mut changed = false;
+            for (index, layer) in layers.iter().enumerate() {
+                let forced = layers
+                    .iter()
+                    .zip(&open)
+                    .enumerate()
+                    .filter(|(other, (other_layer, variants))| {
+                        *other != index && other_layer.required && variants.len() == 1
+                    })
+                    .map(|(_, (other_layer, variants))| (other_layer.name, variants[0]))
+                    .collect::<Vec<(&str, &str)>>();
+
+                let is_dead =
+                    |variant: &str| {
+                        let mut parts = forced.clone();
+                        parts.push((layer.name, variant));
+                        if self.is_banned(&parts) {
+                            return true;
+                        }
+                        layers.iter().zip(&open).enumerate().any(
+                            |(other, (other_layer, variants))| {
+                                other != index
+                                    && other_layer.required
+                                    && variants.len() > 1
+                                    && variants.iter().all(|other_variant| {
+                                        let mut parts = parts.clone();
+                                        parts.push((other_layer.name, other_variant));
+                                        self.is_banned(&parts)
+                                    })
+                            },
+                        )
+                    };
+                let before = open[index].len();
+                let remaining = open[index]
+                    .iter()
+                    .copied()
+                    .filter(|variant| !is_dead(variant))
+                    .collect::<Vec<&'static str>>();
+                if remaining.len() != before {
+                    open[index] = remaining;
+                    changed = true;
+                }
+            }
+            if !changed {
+                return open;
+            }
+        }
+    }
+
+    fn validate_distance(&mut self, rules_file: &Path, layers: &[Layer]) -> Result<()> {
+        let significant = match &self.significant_layers {
+            Some(names) => {
+                if let Some(name) = names
+                    .iter()
+                    .find(|name| !layers.iter().any(|layer| layer.name == name.as_str()))
+                {
+                    return Err(Error::Validation(format!(
+                        "{rules_file:?}: unknown significant layer {name:?}"
+                    )));
+                }
+                layers
+                    .iter()
+                    .filter(|layer| names.iter().any(|name| name == layer.name))
+                    .collect::<Vec<&Layer>>()
+            }
+            None => layers.iter().filter(|layer| !layer.derived).collect(),
+        };
+        self.significant_layers = Some(
+            significant
+                .iter()
+                .map(|layer| layer.name.to_string())
+                .collect(),
+        );
+
+        let min_distance = self.min_distance();
+        if min_distance == 0 || min_distance > significant.len() {
+            return Err(Error::Validation(format!(
+                "{rules_file:?}: min_distance must be between 1 and the {} significant layers, got {min_distance}",
+                significant.len()
+            )));
+        }
+
+        // Two metronions agreeing on any `len - min_distance + 1` significant
+        // layers are too close, so there are at most as many metronions as
+        // combinations of the smallest such layers.
+        let mut sizes = significant
+            .iter()
+            .map(|layer| layer.variants.len() as u128 + u128::from(!layer.required))
+            .collect::<Vec<u128>>();
+        sizes.sort_unstable();
+        self.capacity = Some(
+            sizes
+                .iter()
+                .take(significant.len() - min_distance + 1)
+                .fold(1u128, |capacity, size| capacity.saturating_mul(*size)),
+        );
+
+        Ok(())
+    }
+
+    pub fn min_distance(&self) -> usize {
+        self.min_distance.unwrap_or(1)
+    }
+
+    // How many metronions at most can meet the minimum distance, if set.
+    pub fn capacity(&self) -> Option<u128> {
+        self.capacity.filter(|_| self.min_distance() > 1)
+    }
+
+    // The variant of each significant layer, compared by `is_far_from`.
+    pub fn signature<S: AsRef<str>>(&self, parts: &[(&str, S)]) -> Vec<Option<String>> {
+        self.significant_layers
+            .iter()
+            .flatten()
+            .map(|name| {
+                parts
+                    .iter()
+                    .find(|(layer, _)| layer == name)
+                    .map(|(_, part)| part.as_ref().to_string())
+            })
+            .collect()
+    }
+
+    pub fn is_far_from(&self, signature: &[Option<String>], other: &[Option<String>]) -> bool {
+        let distance = signature
+            .iter()
+            .zip(other)
+            .filter(|(variant, other)| variant != other)
+            .count();
+        distance >= self.min_distance()
+    }
+
+    // Whether the metronion, given as (layer, variant) pairs, has all the
+    // variants of a banned combination.
+    pub fn is_banned<S: AsRef<str>>(&self, parts: &[(&str, S)]) -> bool {
+        self.banned.iter().any(|ban| {
+            ban.iter().all(|(name, variant)| {
+                parts
+                    .iter()
+                    .any(|(layer, part)| layer == name && part.as_ref() == variant)
+            })
+        })
+    }
+
+    pub fn banned(&self) -> &[BTreeMap<String, String>] {
+        &self.banned
+    }
+
+    pub fn banned_count(&self) -> usize {
+        self.banned.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layer(name: &'static str, variants: &[&'static str], required: bool) -> Layer {
+        Layer {
+            name,
+            variants: variants.to_vec(),
+            required,
+            derived: false,
+        }
+    }
+
+    fn rules(json: &str) -> Rules {
+        serde_json::from_str(json).expect("Invalid rules")
+    }
+
+    fn layers() -> Vec<Layer> {
+        vec![
+            layer("Body", &["Body_1", "Body_2"], true),
+            layer("Hand", &["Hand_1", "Hand_2"], true),
+            layer("Acc", &["Acc_1"], false),
+        ]
+    }
+
+    #[test]
+    fn validate_keeps_layers_with_an_open_variant() {
+        let rules = rules(
+            r#"{ "banned": [
+                { "Body": "Body_1" },
+                { "Body": "Body_2", "Hand": "Hand_1" },
+                { "Body": "Body_3", "Hand": "Hand_2" }
+            ] }"#,
+        )
+        .validate(Path::new("rules.json"), &layers())
+        .expect("Rules should be valid");
+
+        // the ban naming a variant outside the catalog is dropped
+        assert_eq!(rules.banned_count(), 2);
+        assert!(rules.is_banned(&[("Body", "Body_2"), ("Hand", "Hand_1")]));
+        assert!(!rules.is_banned(&[("Body", "Body_2"), ("Hand", "Hand_2")]));
+    }
+
+    #[test]
+    fn validate_rejects_a_required_layer_banned_variant_by_variant() {
+        let result = rules(r#"{ "banned": [{ "Hand": "Hand_1" }, { "Hand": "Hand_2" }] }"#)
+            .validate(Path::new("rules.json"), &layers());
+
+        assert!(matches!(result, Err(Error::Validation(message)) if message.contains("Hand")));
+    }
+
+    #[test]
+    fn validate_rejects_a_variant_conflicting_with_every_variant_of_a_required_layer() {
+        // Acc_1 cannot go with either body, so no metronion can have it
+        let result = rules(
+            r#"{ "banned": [
+                { "Acc": "Acc_1", "Body": "Body_1" },
+                { "Acc": "Acc_1", "Body": "Body_2" }
+            ] }"#,
+        )
+        .validate(Path::new("rules.json"), &layers());
+
+        assert!(matches!(result, Err(Error::Validation(message)) if message.contains("Acc")));
+    }
+
+    #[test]
+    fn validate_follows_bans_through_forced_variants() {
+        // banning Body_1 forces Body_2, which rules out Hand_1, which
+        // forces Hand_2, which rules out Body_2
+        let result = rules(
+            r#"{ "banned": [
+                { "Body": "Body_1" },
+                { "Body": "Body_2", "Hand": "Hand_1" },
+                { "Hand": "Hand_2", "Acc": "Acc_1" },
+                { "Hand": "Hand_2", "Body": "Body_2" }
+            ] }"#,
+        )
+        .validate(Path::new("rules.json"), &layers());
+
+        assert!(matches!(result, Err(Error::Validation(_))));
+    }
+
+    fn distance_layers() -> Vec<Layer> {
+        vec![
+            layer("Body", &["Body_1", "Body_2", "Body_3"], true),
+            layer("Hand", &["Hand_1", "Hand_2"], true),
+            // an optional layer also counts having no variant
+            layer("Acc", &["Acc_1"], false),
+        ]
+    }
+
+    fn capacity(json: &str) -> Result<Option<u128>> {
+        rules(json)
+            .validate(Path::new("rules.json"), &distance_layers())
+            .map(|rules| rules.capacity())
+    }
+
+    #[test]
+    fn capacity_is_the_product_of_the_smallest_layers() {
+        // layer sizes 3, 2 and 2: metronions two apart differ in the Hand
+        // and Acc pair, of which there are 2 * 2
+        assert_eq!(capacity(r#"{ "min_distance": 2 }"#).unwrap(), Some(4));
+        // three apart they differ in every layer, so at most 2
+        assert_eq!(capacity(r#"{ "min_distance": 3 }"#).unwrap(), Some(2));
+        assert_eq!(
+            capacity(r#"{ "min_distance": 2, "significant_layers": ["Body", "Hand"] }"#).unwrap(),
+            Some(2)
+        );
+    }
+
+    #[test]
+    fn capacity_is_unbounded_without_a_minimum_distance() {
+        assert_eq!(capacity("{}").unwrap(), None);
+        assert_eq!(capacity(r#"{ "min_distance": 1 }"#).unwrap(), None);
+    }
+
+    #[test]
+    fn validate_rejects_distances_out_of_range() {
+        assert!(matches!(
+            capacity(r#"{ "min_distance": 0 }"#),
+            Err(Error::Validation(_))
+        ));
+        assert!(matches!(
+            capacity(r#"{ "min_distance": 2, "significant_layers": ["Body"] }"#),
+            Err(Error::Validation(_))
+        ));
+        assert!(matches!(
+            capacity(r#"{ "min_distance": 2, "significant_layers": ["Feet"] }"#),
+            Err(Error::Validation(message)) if message.contains("Feet")
+        ));
+    }
+}
@@ -0,0 +1,36 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static CANCELLED: AtomicBool = AtomicBool::new(false);
+
+pub fn is_cancelled() -> bool {
+    CANCELLED.load(Ordering::SeqCst)
+}
+
+// Watch for SIGINT and SIGTERM. The first signal stops scheduling new
+// renders and lets the in-flight ones finish, a second one exits right away.
+pub fn listen() {
+    tokio::spawn(async {
+        while tokio::signal::ctrl_c().await.is_ok() {
+            on_signal();
+        }
+    });
+
+    #[cfg(unix)]
+    tokio::spawn(async {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        if let Ok(mut terminate) = signal(SignalKind::terminate()) {
+            while terminate.recv().await.is_some() {
+                on_signal();
+            }
+        }
+    });
+}
+
+fn on_signal() {
+    if CANCELLED.swap(true, Ordering::SeqCst) {
+        error!("Interrupted again, exiting without saving a checkpoint");
+        std::process::exit(130);
+    }
+    warn!("Interrupted, finishing in-flight renders (press Ctrl-C again to exit now)");
+}
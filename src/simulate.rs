@@ -0,0 +1,132 @@
+use serde::Serialize;
+use std::collections::HashMap;
+
+use crate::error::Result;
+
+pub struct Variant {
+    pub layer: &'static str,
+    pub variant: &'static str,
+    pub rarity: &'static str,
+}
+
+// Realised supply of one variant over all simulated collections.
+#[derive(Debug, Serialize)]
+pub struct VariantSupply {
+    pub layer: &'static str,
+    pub variant: &'static str,
+    pub rarity: &'static str,
+    pub mean: f64,
+    pub min: usize,
+    pub p5: usize,
+    pub p50: usize,
+    pub p95: usize,
+    pub max: usize,
+    // share of the collections without this variant
+    pub zero: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Simulation {
+    pub runs: usize,
+    pub count: usize,
+    pub watched_rarity: &'static str,
+    // share of the collections where a variant of the watched rarity has
+    // no supply at all
+    pub any_watched_zero: f64,
+    pub variants: Vec<VariantSupply>,
+}
+
+// Sample `runs` collections of `count` metronions, spread over `jobs`
+// threads, and summarise how much the supply of every variant varies.
+pub fn simulate<F>(
+    variants: &[Variant],
+    runs: usize,
+    count: usize,
+    jobs: usize,
+    watched_rarity: &'static str,
+    sample: F,
+) -> Result<Simulation>
+where
+    F: Fn() -> Result<Vec<String>> + Sync,
+{
+    let index = variants
+        .iter()
+        .enumerate()
+        .map(|(i, variant)| (variant.variant, i))
+        .collect::<HashMap<&str, usize>>();
+
+    let simulate_run = || -> Result<Vec<usize>> {
+        let mut supply = vec![0; variants.len()];
+        for _ in 0..count {
+            for part in sample()? {
+                if let Some(i) = index.get(part.as_str()) {
+                    supply[*i] += 1;
+                }
+            }
+        }
+        Ok(supply)
+    };
+
+    // supply of every variant, one row per run
+    let jobs = jobs.clamp(1, runs.max(1));
+    let mut rows = Vec::with_capacity(runs);
+    std::thread::scope(|scope| -> Result<()> {
+        let workers = (0..jobs)
+            .map(|job| {
+                let runs = runs / jobs + usize::from(job < runs % jobs);
+                scope.spawn(move || {
+                    (0..runs)
+                        .map(|_| simulate_run())
+                        .collect::<Result<Vec<_>>>()
+                })
+            })
+            .collect::<Vec<_>>();
+        for worker in workers {
+            rows.extend(worker.join().expect("Simulation thread panicked")?);
+        }
+        Ok(())
+    })?;
+
+    let any_watched_zero = rows
+        .iter()
+        .filter(|supply| {
+            variants
+                .iter()
+                .zip(supply.iter())
+                .any(|(variant, supply)| variant.rarity == watched_rarity && *supply == 0)
+        })
+        .count() as f64
+        / runs.max(1) as f64;
+
+    let variants = variants
+        .iter()
+        .enumerate()
+        .map(|(i, variant)| {
+            let mut supply = rows.iter().map(|row| row[i]).collect::<Vec<usize>>();
+            supply.sort_unstable();
+            let percentile = |p: usize| supply[(supply.len() * p / 100).min(supply.len() - 1)];
+
+            VariantSupply {
+                layer: variant.layer,
+                variant: variant.variant,
+                rarity: variant.rarity,
+                mean: supply.iter().sum::<usize>() as f64 / supply.len() as f64,
+                min: supply[0],
+                p5: percentile(5),
+                p50: percentile(50),
+                p95: percentile(95),
+                max: supply[supply.len() - 1],
+                zero: supply.iter().filter(|supply| **supply == 0).count() as f64
+                    / supply.len() as f64,
+            }
+        })
+        .collect();
+
+    Ok(Simulation {
+        runs,
+        count,
+        watched_rarity,
+        any_watched_zero,
+        variants,
+    })
+}
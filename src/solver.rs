@@ -0,0 +1,805 @@
+use rand::prelude::*;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use crate::error::{Error, IoContext, Result};
+use crate::stats::{Model, Sampling};
+
+// Exact number of metronions per variant, by layer, e.g.
+//
+//   { "Background": { "NFT_BG_8": 10, "NFT_BG_11": 10, ... } }
+//
+// Layers left out get quotas rounded from their exact probabilities.
+pub type Quotas = BTreeMap<String, BTreeMap<String, usize>>;
+
+// Swaps tried to fix a metronion before starting over.
+const MAX_SWAP_ATTEMPTS: usize = 1_000;
+// Fresh assignments tried before giving up.
+const MAX_SOLVE_ATTEMPTS: usize = 10;
+
+pub fn load_quotas(quotas_file: &Path) -> Result<Quotas> {
+    let contents = std::fs::read(quotas_file).with_path(quotas_file)?;
+    serde_json::from_slice(&contents).map_err(|err| Error::Parse {
+        path: quotas_file.to_path_buf(),
+        line: err.line(),
+        message: err.to_string(),
+    })
+}
+
+// Metronions accepted so far, deciding whether another one may join.
+pub trait Accepted {
+    // Why the metronion, as (layer, variant) pairs, cannot be added.
+    fn conflict(&self, token: &[(&'static str, String)]) -> Option<String>;
+    fn accept(&mut self, token: &[(&'static str, String)]);
+    fn remove(&mut self, token: &[(&'static str, String)]);
+}
+
+// Variant of every sampled layer of one metronion, `None` if left out.
+type Token = Vec<Option<&'static str>>;
+
+// Hands out exact variant counts over a batch of metronions.
+//
+// `new` turns the quotas into a pool of values per layer and checks the
+// conditions any assignment needs: quotas adding up, conditional layers
+// fitting their anchor, outfit sets fitting the quotas, banned combinations
+// not forced by the counts and enough distinct combinations. `solve` then
+// searches for an assignment: it deals the pools at random and swaps values
+// between metronions until every one of them is accepted, starting over a
+// few times. The search is best-effort, so failing it does not prove that
+// no assignment exists.
+pub struct Solver<'a> {
+    model: &'a Model,
+    // the values each sampled layer hands out, `None` for no variant
+    pools: Vec<(usize, Vec<Option<&'static str>>)>,
+    anchor: usize,
+    // the variants forced on the first metronions by the outfit sets they
+    // wear, with the index of the set in the model
+    forced: Vec<(usize, Token)>,
+}
+
+impl<'a> Solver<'a> {
+    // Turn the quotas into the values left for `count` sampled metronions
+    // once the `pinned` ones are placed, and check them against the model
+    // and the `banned` combinations. The quotas are spread over the sampled
+    // metronions and the pinned ones with layers, a pinned image (`None`)
+    // has no variant to count.
+    pub fn new(
+        model: &'a Model,
+        quotas: &Quotas,
+        count: usize,
+        pinned: &[Option<Vec<(&'static str, String)>>],
+        banned: &[BTreeMap<String, String>],
+    ) -> Result<Self> {
+        let pinned = pinned.iter().flatten().collect::<Vec<_>>();
+        let total = count + pinned.len();
+        if let Some(layer) = quotas.keys().find(|name| {
+            !model.layers.iter().any(|layer| {
+                layer.name == name.as_str() && !matches!(layer.sampling, Sampling::Derived(_))
+            })
+        }) {
+            return Err(Error::Validation(format!(
+                "quotas for unknown or derived layer {layer:?}"
+            )));
+        }
+
+        let anchor = model
+            .layers
+            .iter()
+            .position(|layer| layer.name == model.anchor)
+            .ok_or_else(|| Error::Catalog(format!("missing anchor layer {}", model.anchor)))?;
+        let stats = model.stats()?;
+
+        let mut problems = vec![];
+        let mut pools = vec![];
+        for (index, (layer, layer_stats)) in model.layers.iter().zip(&stats.layers).enumerate() {
+            if matches!(layer.sampling, Sampling::Derived(_)) {
+                continue;
+            }
+
+            let mut counts = match quotas.get(layer.name) {
+                Some(layer_quotas) => match layer_counts(model, index, layer_quotas, total) {
+                    Ok(counts) => counts,
+                    Err(problem) => {
+                        problems.push(problem);
+                        continue;
+                    }
+                },
+                None => {
+                    let mut options = layer_stats
+                        .variants
+                        .iter()
+                        .map(|variant| {
+                            let name = layer
+                                .tiers
+                                .iter()
+                                .flatten()
+                                .find(|name| **name == variant.variant)
+                                .copied();
+                            (name, variant.probability)
+                        })
+                        .collect::<Vec<_>>();
+                    if layer_stats.none > 0.0 {
+                        options.push((None, layer_stats.none));
+                    }
+                    round_counts(&options, total)
+                }
+            };
+
+            for token in &pinned {
+                let value = token
+                    .iter()
+                    .find(|(name, _)| *name == layer.name)
+                    .map(|(_, variant)| variant.as_str());
+                let entry = counts
+                    .iter_mut()
+                    .find(|(option, _)| option.map(str::to_string).as_deref() == value);
+                match entry {
+                    Some((_, count)) if *count > 0 => *count -= 1,
+                    _ => problems.push(format!(
+                        "pinned metronions use {} of layer {} more often than its quota",
+                        value.unwrap_or("no variant"),
+                        layer.name
+                    )),
+                }
+            }
+
+            let pool = counts
+                .into_iter()
+                .flat_map(|(option, count)| std::iter::repeat_n(option, count))
+                .collect::<Vec<_>>();
+            if pool.len() != count {
+                problems.push(format!(
+                    "quotas of layer {} leave {} values for {count} metronions",
+                    layer.name,
+                    pool.len()
+                ));
+            }
+            pools.push((index, pool));
+        }
+
+        // the sets are rolled like the rarity of a layer
+        let mut set_options = stats
+            .sets
+            .iter()
+            .enumerate()
+            .map(|(set, set_stats)| (Some(set), set_stats.probability))
+            .collect::<Vec<_>>();
+        set_options.push((None, 1.0 - set_options.iter().map(|(_, p)| p).sum::<f64>()));
+        let mut forced = vec![];
+        for (set, set_count) in round_counts(&set_options, total) {
+            let Some(set) = set else {
+                continue;
+            };
+            let mut token = vec![None; model.layers.len()];
+            for (name, variant) in &model.sets[set].layers {
+                if let Some(index) = model.layers.iter().position(|layer| layer.name == *name) {
+                    token[index] = Some(*variant);
+                }
+            }
+            forced.extend(std::iter::repeat_n((set, token), set_count));
+        }
+        if forced.len() > count {
+            problems.push(format!(
+                "outfit sets are worn by {} metronions, only {count} are sampled",
+                forced.len()
+            ));
+        }
+
+        let solver = Solver {
+            model,
+            pools,
+            anchor,
+            forced,
+        };
+        if problems.is_empty() {
+            solver.check(count, banned, &mut problems);
+        }
+        if !problems.is_empty() {
+            return Err(Error::Validation(format!(
+                "the quotas cannot be met: {}",
+                problems.join("; ")
+            )));
+        }
+        Ok(solver)
+    }
+
+    // Conditions every assignment of the pools to `count` metronions needs.
+    fn check(&self, count: usize, banned: &[BTreeMap<String, String>], problems: &mut Vec<String>) {
+        // a conditional layer can only hand out variants to the metronions
+        // whose anchor variant allows it, unless a set forces them
+        let anchors = self.pool(self.anchor);
+        for (index, pool) in &self.pools {
+            let Sampling::If(allows) = &self.model.layers[*index].sampling else {
+                continue;
+            };
+
+            let needed = pool
+                .iter()
+                .filter(|value| value.is_some())
+                .count()
+                .saturating_sub(self.forced_count(*index, None));
+            let allowing = anchors
+                .iter()
+                .filter(|anchor| anchor.is_some_and(allows))
+                .count();
+            if needed > allowing {
+                problems.push(format!(
+                    "layer {} has quotas for {needed} metronions but the {} quotas only allow it on {allowing}",
+                    self.model.layers[*index].name, self.model.anchor
+                ));
+            }
+        }
+
+        for (index, pool) in &self.pools {
+            let mut variants = pool.iter().flatten().copied().collect::<Vec<&str>>();
+            variants.dedup();
+            for variant in variants {
+                let needed = self.forced_count(*index, Some(variant));
+                let quota = pool.iter().filter(|value| **value == Some(variant)).count();
+                if needed > quota {
+                    problems.push(format!(
+                        "outfit sets need {variant} of layer {} on {needed} metronions, its quota is {quota}",
+                        self.model.layers[*index].name
+                    ));
+                }
+            }
+        }
+
+        // metronions without a banned variant leave room for at most
+        // `count` of each other variant of the combination
+        for ban in banned {
+            let counts = ban
+                .iter()
+                .map(|(layer, variant)| self.variant_count(layer, variant))
+                .collect::<Vec<usize>>();
+            let forced = counts
+                .iter()
+                .sum::<usize>()
+                .saturating_sub((ban.len() - 1) * count);
+            if forced > 0 {
+                problems.push(format!(
+                    "banned combination {ban:?} is forced on {forced} metronions by quotas of {counts:?}"
+                ));
+            }
+        }
+
+        let combinations = self
+            .pools
+            .iter()
+            .map(|(_, pool)| {
+                let mut values = pool.clone();
+                values.sort_unstable();
+                values.dedup();
+                values.len() as u128
+            })
+            .fold(1u128, u128::saturating_mul);
+        if combinations < count as u128 {
+            problems.push(format!(
+                "the quotas allow at most {combinations} distinct metronions, {count} are sampled"
+            ));
+        }
+    }
+
+    fn pool(&self, index: usize) -> &[Option<&'static str>] {
+        self.pools
+            .iter()
+            .find(|(layer, _)| *layer == index)
+            .map(|(_, pool)| pool.as_slice())
+            .unwrap_or_default()
+    }
+
+    // How many values of the layer the sets force, of one variant or of
+    // any when not given.
+    fn forced_count(&self, index: usize, variant: Option<&str>) -> usize {
+        self.forced
+            .iter()
+            .filter(|(_, token)| {
+                token[index].is_some() && (variant.is_none() || token[index] == variant)
+            })
+            .count()
+    }
+
+    // How many metronions the pools give the variant, following the anchor
+    // for a derived layer.
+    fn variant_count(&self, layer: &str, variant: &str) -> usize {
+        let Some(index) = self
+            .model
+            .layers
+            .iter()
+            .position(|other| other.name == layer)
+        else {
+            return 0;
+        };
+        match &self.model.layers[index].sampling {
+            Sampling::Derived(derive) => self
+                .pool(self.anchor)
+                .iter()
+                .filter(|anchor| anchor.and_then(derive).as_deref() == Some(variant))
+                .count(),
+            _ => self
+                .pool(index)
+                .iter()
+                .filter(|value| **value == Some(variant))
+                .count(),
+        }
+    }
+
+    fn is_fixed(&self, token: usize, index: usize) -> bool {
+        self.forced
+            .get(token)
+            .is_some_and(|(_, forced)| forced[index].is_some())
+    }
+
+    // Assign the quotas to the metronions so every one of them is accepted,
+    // as the index of the set each one wears and its variants. See `Solver`
+    // for why this may fail even though an assignment exists.
+    pub fn solve<A: Accepted>(
+        &self,
+        count: usize,
+        new_accepted: impl Fn() -> A,
+    ) -> Result<Vec<(Option<usize>, Vec<String>)>> {
+        let mut failures = BTreeMap::new();
+        for _ in 0..MAX_SOLVE_ATTEMPTS {
+            let mut accepted = new_accepted();
+            let mut tokens = self.assign(count);
+
+            match self.repair(&mut tokens, &mut accepted) {
+                Ok(()) => {
+                    let mut solved = tokens
+                        .iter()
+                        .enumerate()
+                        .map(|(i, token)| {
+                            let set = self.forced.get(i).map(|(set, _)| *set);
+                            let parts = self
+                                .layers(token)
+                                .into_iter()
+                                .map(|(_, variant)| variant)
+                                .collect();
+                            (set, parts)
+                        })
+                        .collect::<Vec<_>>();
+                    // the set wearers come first until shuffled
+                    solved.shuffle(&mut thread_rng());
+                    return Ok(solved);
+                }
+                Err(last) => failures = last,
+            }
+        }
+
+        let failures = failures
+            .iter()
+            .map(|(reason, count)| format!("{count} {reason}"))
+            .collect::<Vec<String>>();
+        Err(Error::Validation(format!(
+            "no assignment meeting the quotas and rules found in {MAX_SOLVE_ATTEMPTS} attempts of a best-effort search, the last one left metronions that {}",
+            failures.join(", ")
+        )))
+    }
+
+    // Deal the pools at random, giving conditional variants to the
+    // metronions allowing them first. The set wearers keep their forced
+    // variants.
+    fn assign(&self, count: usize) -> Vec<Token> {
+        let mut rng = thread_rng();
+        let mut tokens = vec![vec![None; self.model.layers.len()]; count];
+        for (token, (_, forced)) in tokens.iter_mut().zip(&self.forced) {
+            token.clone_from(forced);
+        }
+
+        for (index, pool) in &self.pools {
+            let mut pool = pool.clone();
+            for (_, forced) in &self.forced {
+                if let Some(position) = forced[*index]
+                    .and_then(|variant| pool.iter().position(|value| *value == Some(variant)))
+                {
+                    pool.swap_remove(position);
+                }
+            }
+            pool.shuffle(&mut rng);
+            let free = (0..count)
+                .filter(|i| !self.is_fixed(*i, *index))
+                .collect::<Vec<usize>>();
+
+            if let Sampling::If(allows) = &self.model.layers[*index].sampling {
+                let (variants, nones): (Vec<_>, Vec<_>) =
+                    pool.into_iter().partition(Option::is_some);
+                let (mut allowing, others): (Vec<usize>, Vec<usize>) = free
+                    .into_iter()
+                    .partition(|i| tokens[*i][self.anchor].is_some_and(allows));
+                allowing.shuffle(&mut rng);
+
+                // variants left over go anywhere for `repair` to move
+                let mut values = variants.into_iter().chain(nones);
+                for i in allowing.iter().chain(&others) {
+                    tokens[*i][*index] = values.next().flatten();
+                }
+            } else {
+                for (i, value) in free.into_iter().zip(pool) {
+                    tokens[i][*index] = value;
+                }
+            }
+        }
+
+        tokens
+    }
+
+    // Accept the metronions in order, swapping layer values with other ones
+    // until each of them fits. Swapping with an accepted metronion needs it
+    // to still fit afterwards, and the variants forced by a set never move.
+    // Gives the number of metronions left unfit by reason.
+    fn repair<A: Accepted>(
+        &self,
+        tokens: &mut [Token],
+        accepted: &mut A,
+    ) -> std::result::Result<(), BTreeMap<String, usize>> {
+        let mut rng = thread_rng();
+        let layers = self
+            .pools
+            .iter()
+            .map(|(index, _)| *index)
+            .collect::<Vec<_>>();
+
+        let mut failures = BTreeMap::new();
+        for i in 0..tokens.len() {
+            let conflict = accepted.conflict(&self.layers(&tokens[i]));
+            let is_consistent = self.is_consistent(tokens, i);
+            let mut fits = conflict.is_none() && is_consistent;
+            for _ in 0..MAX_SWAP_ATTEMPTS {
+                if fits || tokens.len() == 1 {
+                    break;
+                }
+
+                // a duplicate needs several layers changed at once
+                let j = rng.gen_range(0..tokens.len() - 1);
+                let j = if j >= i { j + 1 } else { j };
+                let amount = rng.gen_range(1..=layers.len());
+                let swapped = layers
+                    .choose_multiple(&mut rng, amount)
+                    .copied()
+                    .filter(|layer| !self.is_fixed(i, *layer) && !self.is_fixed(j, *layer))
+                    .collect::<Vec<usize>>();
+                if swapped.is_empty() {
+                    continue;
+                }
+
+                let is_accepted = j < i;
+                if is_accepted {
+                    accepted.remove(&self.layers(&tokens[j]));
+                }
+                swap(tokens, i, j, &swapped);
+                fits = self.is_consistent(tokens, i)
+                    && self.is_consistent(tokens, j)
+                    && accepted.conflict(&self.layers(&tokens[i])).is_none();
+                if fits && is_accepted {
+                    accepted.accept(&self.layers(&tokens[i]));
+                    fits = accepted.conflict(&self.layers(&tokens[j])).is_none();
+                    accepted.remove(&self.layers(&tokens[i]));
+                }
+                if !fits {
+                    swap(tokens, i, j, &swapped);
+                }
+                if is_accepted {
+                    accepted.accept(&self.layers(&tokens[j]));
+                }
+            }
+
+            if fits {
+                accepted.accept(&self.layers(&tokens[i]));
+            } else {
+                let reason = conflict.unwrap_or_else(|| {
+                    format!("have a variant their {} does not allow", self.model.anchor)
+                });
+                *failures.entry(reason).or_default() += 1;
+            }
+        }
+
+        if failures.is_empty() {
+            Ok(())
+        } else {
+            Err(failures)
+        }
+    }
+
+    // Conditional layers only have a variant when the anchor allows it,
+    // which the sets forcing one also force.
+    fn is_consistent(&self, tokens: &[Token], i: usize) -> bool {
+        let token = &tokens[i];
+        self.model
+            .layers
+            .iter()
+            .zip(token)
+            .all(|(layer, value)| match (&layer.sampling, value) {
+                (Sampling::If(allows), Some(_)) => token[self.anchor].is_some_and(allows),
+                _ => true,
+            })
+    }
+
+    // The metronion as (layer, variant) pairs in drawing order, with the
+    // derived layers filled in.
+    fn layers(&self, token: &Token) -> Vec<(&'static str, String)> {
+        self.model
+            .layers
+            .iter()
+            .zip(token)
+            .filter_map(|(layer, value)| match &layer.sampling {
+                Sampling::Derived(derive) => token[self.anchor]
+                    .and_then(derive)
+                    .map(|variant| (layer.name, variant)),
+                _ => value.map(|variant| (layer.name, variant.to_string())),
+            })
+            .collect()
+    }
+}
+
+fn swap(tokens: &mut [Token], i: usize, j: usize, layers: &[usize]) {
+    for layer in layers {
+        let value = tokens[i][*layer];
+        tokens[i][*layer] = tokens[j][*layer];
+        tokens[j][*layer] = value;
+    }
+}
+
+// Quotas given for a layer, with the rest of the metronions getting no
+// variant when the layer allows it.
+fn layer_counts(
+    model: &Model,
+    index: usize,
+    layer_quotas: &BTreeMap<String, usize>,
+    total: usize,
+) -> std::result::Result<Vec<(Option<&'static str>, usize)>, String> {
+    let layer = &model.layers[index];
+    let mut counts = vec![];
+    for (variant, count) in layer_quotas {
+        let variant = layer
+            .tiers
+            .iter()
+            .flatten()
+            .find(|name| **name == variant.as_str())
+            .ok_or_else(|| {
+                format!(
+                    "quota for {variant:?} which is not a variant of layer {}",
+                    layer.name
+                )
+            })?;
+        counts.push((Some(*variant), *count));
+    }
+
+    let sum = counts.iter().map(|(_, count)| count).sum::<usize>();
+    match layer.sampling {
+        Sampling::Required if sum != total => Err(format!(
+            "quotas of layer {} add up to {sum}, every one of the {total} metronions needs a variant",
+            layer.name
+        )),
+        _ if sum > total => Err(format!(
+            "quotas of layer {} add up to {sum}, more than the {total} metronions",
+            layer.name
+        )),
+        _ => {
+            counts.push((None, total - sum));
+            Ok(counts)
+        }
+    }
+}
+
+// Largest remainder rounding of `total` over the options' probabilities.
+fn round_counts<T: Copy>(options: &[(T, f64)], total: usize) -> Vec<(T, usize)> {
+    let sum = options
+        .iter()
+        .map(|(_, probability)| probability)
+        .sum::<f64>();
+    let exact = options
+        .iter()
+        .map(|(_, probability)| probability / sum * total as f64)
+        .collect::<Vec<f64>>();
+
+    let mut counts = exact
+        .iter()
+        .map(|exact| exact.floor() as usize)
+        .collect::<Vec<usize>>();
+    let mut order = (0..options.len()).collect::<Vec<usize>>();
+    order.sort_by(|a, b| {
+        (exact[*b] - exact[*b].floor()).total_cmp(&(exact[*a] - exact[*a].floor()))
+    });
+    let missing = total - counts.iter().sum::<usize>();
+    for i in order.into_iter().take(missing) {
+        counts[i] += 1;
+    }
+
+    options
+        .iter()
+        .zip(counts)
+        .map(|((option, _), count)| (*option, count))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stats::{test_model, Layer, Set};
+
+    // 8 metronions get 6 Body_1 and 2 Body_2, half of each hand and 2 of
+    // each eyes
+    fn model(sets: Vec<Set>) -> Model {
+        test_model(
+            vec![Layer {
+                name: "Eyes",
+                tiers: vec![vec!["Eyes_1", "Eyes_2", "Eyes_3", "Eyes_4"], vec![]],
+                sampling: Sampling::Required,
+            }],
+            sets,
+        )
+    }
+
+    // Accepts any metronion without a banned combination.
+    struct Bans<'a>(&'a [BTreeMap<String, String>]);
+
+    impl Accepted for Bans<'_> {
+        fn conflict(&self, token: &[(&'static str, String)]) -> Option<String> {
+            self.0
+                .iter()
+                .any(|ban| {
+                    ban.iter().all(|(layer, variant)| {
+                        token
+                            .iter()
+                            .any(|(name, part)| name == layer && part == variant)
+                    })
+                })
+                .then(|| "have a banned combination".to_string())
+        }
+
+        fn accept(&mut self, _token: &[(&'static str, String)]) {}
+
+        fn remove(&mut self, _token: &[(&'static str, String)]) {}
+    }
+
+    fn ban(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        pairs
+            .iter()
+            .map(|(layer, variant)| (layer.to_string(), variant.to_string()))
+            .collect()
+    }
+
+    fn count(solved: &[(Option<usize>, Vec<String>)], variant: &str) -> usize {
+        solved
+            .iter()
+            .filter(|(_, parts)| parts.iter().any(|part| part == variant))
+            .count()
+    }
+
+    #[test]
+    fn solve_meets_the_quotas_and_the_bans() {
+        let model = model(vec![]);
+        let banned = [ban(&[("Body", "Body_2"), ("Hand", "Hand_1")])];
+        let solver = Solver::new(&model, &Quotas::new(), 8, &[], &banned).unwrap();
+
+        let solved = solver.solve(8, || Bans(&banned)).unwrap();
+
+        assert_eq!(solved.len(), 8);
+        assert_eq!(count(&solved, "Body_1"), 6);
+        assert_eq!(count(&solved, "Body_2"), 2);
+        assert_eq!(count(&solved, "Hand_1"), 4);
+        assert_eq!(count(&solved, "Hand_2"), 4);
+        assert!(solved.iter().all(|(_, parts)| {
+            !(parts.contains(&"Body_2".to_string()) && parts.contains(&"Hand_1".to_string()))
+        }));
+    }
+
+    #[test]
+    fn solve_gives_the_set_wearers_their_variants() {
+        // Rare is rolled for a quarter of the metronions, all wearing the set
+        let model = model(vec![Set {
+            name: "Gloved",
+            tier: 1,
+            layers: vec![("Hand", "Hand_2")],
+        }]);
+        let solver = Solver::new(&model, &Quotas::new(), 8, &[], &[]).unwrap();
+
+        let solved = solver.solve(8, || Bans(&[])).unwrap();
+
+        let wearers = solved
+            .iter()
+            .filter(|(set, _)| *set == Some(0))
+            .collect::<Vec<_>>();
+        assert_eq!(wearers.len(), 2);
+        assert!(wearers
+            .iter()
+            .all(|(_, parts)| parts.contains(&"Hand_2".to_string())));
+        // 3 of the 6 metronions without the set, then the 2 wearers
+        assert_eq!(count(&solved, "Hand_2"), 5);
+    }
+
+    #[test]
+    fn new_leaves_pinned_images_out_of_the_quotas() {
+        let model = model(vec![]);
+        let quotas = Quotas::from([(
+            "Body".to_string(),
+            BTreeMap::from([("Body_1".to_string(), 6), ("Body_2".to_string(), 2)]),
+        )]);
+        let parts = |body: &str, hand: &str| {
+            Some(vec![
+                ("Body", body.to_string()),
+                ("Hand", hand.to_string()),
+                ("Eyes", "Eyes_1".to_string()),
+            ])
+        };
+        // 8 metronions with layers, 2 of them pinned, and 2 pinned images
+        let pinned = [
+            parts("Body_1", "Hand_1"),
+            None,
+            parts("Body_2", "Hand_2"),
+            None,
+        ];
+        let solver = Solver::new(&model, &quotas, 6, &pinned, &[]).unwrap();
+
+        let solved = solver.solve(6, || Bans(&[])).unwrap();
+
+        assert_eq!(solved.len(), 6);
+        assert_eq!(count(&solved, "Body_1"), 5);
+        assert_eq!(count(&solved, "Body_2"), 1);
+        assert_eq!(count(&solved, "Hand_1"), 3);
+        // both pinned metronions use up the 2 Eyes_1
+        assert_eq!(count(&solved, "Eyes_1"), 0);
+    }
+
+    #[test]
+    fn new_rejects_a_ban_forced_by_the_quotas() {
+        let model = model(vec![]);
+        let quotas = Quotas::from([(
+            "Body".to_string(),
+            BTreeMap::from([("Body_1".to_string(), 3), ("Body_2".to_string(), 5)]),
+        )]);
+        // 5 Body_2 and 4 Hand_1 among 8 metronions meet at least once
+        let banned = [ban(&[("Body", "Body_2"), ("Hand", "Hand_1")])];
+
+        let result = Solver::new(&model, &quotas, 8, &[], &banned);
+
+        assert!(matches!(
+            result,
+            Err(Error::Validation(message)) if message.contains("forced on 1 metronions")
+        ));
+    }
+
+    #[test]
+    fn new_lists_every_conflicting_quota() {
+        let model = model(vec![]);
+        let quotas = Quotas::from([
+            (
+                "Body".to_string(),
+                BTreeMap::from([("Body_1".to_string(), 3)]),
+            ),
+            (
+                "Hand".to_string(),
+                BTreeMap::from([("Hand_1".to_string(), 9)]),
+            ),
+        ]);
+
+        let Err(Error::Validation(message)) = Solver::new(&model, &quotas, 8, &[], &[]) else {
+            panic!("Quotas should conflict");
+        };
+
+        assert!(message.contains("layer Body add up to 3"), "{message}");
+        assert!(message.contains("layer Hand add up to 9"), "{message}");
+    }
+
+    #[test]
+    fn new_rejects_more_metronions_than_distinct_combinations() {
+        let model = model(vec![]);
+        let quotas = Quotas::from([
+            (
+                "Hand".to_string(),
+                BTreeMap::from([("Hand_1".to_string(), 8)]),
+            ),
+            (
+                "Eyes".to_string(),
+                BTreeMap::from([("Eyes_1".to_string(), 8)]),
+            ),
+        ]);
+
+        let result = Solver::new(&model, &quotas, 8, &[], &[]);
+
+        assert!(matches!(
+            result,
+            Err(Error::Validation(message)) if message.contains("at most 2 distinct")
+        ));
+    }
+}
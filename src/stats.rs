@@ -0,0 +1,509 @@
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+use crate::error::{Error, Result};
+
+// Decides from the anchor variant whether a layer is sampled, or which
+// variant it follows.
+pub type Condition = Box<dyn Fn(&str) -> bool>;
+pub type Derivation = Box<dyn Fn(&str) -> Option<String>>;
+
+// How a layer picks its variant, mirroring `generate_random_metronion`.
+pub enum Sampling {
+    // rolls the rarity again until it has variants
+    Required,
+    // a rarity without variants leaves the layer out
+    Optional,
+    // like `Optional`, only when the anchor variant allows it
+    If(Condition),
+    // follows the anchor variant
+    Derived(Derivation),
+}
+
+pub struct Layer {
+    pub name: &'static str,
+    pub tiers: Vec<Vec<&'static str>>,
+    pub sampling: Sampling,
+}
+
+// An outfit set rolled before the layers, forcing their variants.
+pub struct Set {
+    pub name: &'static str,
+    pub tier: usize,
+    pub layers: Vec<(&'static str, &'static str)>,
+}
+
+// The catalog of one gender with the probability of each rarity tier.
+// Conditional and derived layers depend on the anchor layer.
+pub struct Model {
+    pub tier_names: Vec<&'static str>,
+    pub tier_probabilities: Vec<f64>,
+    pub anchor: &'static str,
+    pub layers: Vec<Layer>,
+    pub sets: Vec<Set>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct VariantStats {
+    pub variant: String,
+    pub rarity: &'static str,
+    pub probability: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LayerStats {
+    pub layer: &'static str,
+    // probability that the layer is left out
+    pub none: f64,
+    pub variants: Vec<VariantStats>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SetStats {
+    pub set: &'static str,
+    pub rarity: &'static str,
+    pub probability: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Stats {
+    pub combinations: u128,
+    pub sets: Vec<SetStats>,
+    pub layers: Vec<LayerStats>,
+}
+
+impl Model {
+    fn layer(&self, name: &str) -> Result<&Layer> {
+        self.layers
+            .iter()
+            .find(|layer| layer.name == name)
+            .ok_or_else(|| Error::Catalog(format!("missing layer {name}")))
+    }
+
+    // A variant sampled for sure, checked against the catalog.
+    fn certain(&self, layer: &Layer, variant: &str) -> Result<VariantStats> {
+        let rarity = layer
+            .tiers
+            .iter()
+            .zip(&self.tier_names)
+            .find(|(tier, _)| tier.contains(&variant))
+            .map(|(_, rarity)| *rarity)
+            .ok_or_else(|| {
+                Error::Catalog(format!(
+                    "{variant} is not a variant of layer {}",
+                    layer.name
+                ))
+            })?;
+        Ok(VariantStats {
+            variant: variant.to_string(),
+            rarity,
+            probability: 1.0,
+        })
+    }
+
+    // Every set forces layers it can: no derived layer, and a conditional
+    // one only along with an anchor variant allowing it, as the anchor
+    // rolled otherwise may not.
+    pub fn check_sets(&self) -> std::result::Result<(), String> {
+        for set in &self.sets {
+            let anchor = set
+                .layers
+                .iter()
+                .find(|(name, _)| *name == self.anchor)
+                .map(|(_, variant)| *variant);
+            for (name, variant) in &set.layers {
+                let layer = self.layer(name).map_err(|err| err.to_string())?;
+                match &layer.sampling {
+                    Sampling::Derived(_) => {
+                        return Err(format!("set {} forces derived layer {name}", set.name))
+                    }
+                    Sampling::If(allows) if !anchor.is_some_and(allows) => {
+                        return Err(format!(
+                            "set {} forces {variant} of layer {name} without a {} variant it fits",
+                            set.name, self.anchor
+                        ))
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Probability of every set, rolled like an optional layer.
+    fn set_stats(&self) -> Result<Vec<SetStats>> {
+        self.check_sets().map_err(Error::Catalog)?;
+        let total = self.tier_probabilities.iter().sum::<f64>();
+        self.sets
+            .iter()
+            .map(|set| {
+                let (Some(rarity), Some(probability)) = (
+                    self.tier_names.get(set.tier),
+                    self.tier_probabilities.get(set.tier),
+                ) else {
+                    return Err(Error::Catalog(format!(
+                        "set {} has an unknown rarity",
+                        set.name
+                    )));
+                };
+                let same_tier = self.sets.iter().filter(|other| other.tier == set.tier);
+                Ok(SetStats {
+                    set: set.name,
+                    rarity,
+                    probability: probability / total / same_tier.count() as f64,
+                })
+            })
+            .collect()
+    }
+
+    // Variants of a layer with their probability when sampled on its own,
+    // and the probability of no variant.
+    fn distribution(&self, layer: &Layer) -> Result<(Vec<VariantStats>, f64)> {
+        let total = self.tier_probabilities.iter().sum::<f64>();
+        let empty = layer
+            .tiers
+            .iter()
+            .zip(&self.tier_probabilities)
+            .filter(|(tier, _)| tier.is_empty())
+            .map(|(_, probability)| probability / total)
+            .sum::<f64>();
+
+        // retrying empty tiers renormalises over the others
+        let scale = match layer.sampling {
+            Sampling::Required if empty >= 1.0 => {
+                return Err(Error::Catalog(format!(
+                    "layer {} has no variant for any rarity",
+                    layer.name
+                )))
+            }
+            Sampling::Required => 1.0 / (total * (1.0 - empty)),
+            _ => 1.0 / total,
+        };
+        let none = match layer.sampling {
+            Sampling::Required => 0.0,
+            _ => empty,
+        };
+
+        let variants = layer
+            .tiers
+            .iter()
+            .zip(&self.tier_probabilities)
+            .zip(&self.tier_names)
+            .flat_map(|((tier, probability), rarity)| {
+                tier.iter().map(move |variant| VariantStats {
+                    variant: variant.to_string(),
+                    rarity,
+                    probability: probability * scale / tier.len() as f64,
+                })
+            })
+            .collect();
+
+        Ok((variants, none))
+    }
+
+    // Probability of every variant when the `forced` layers have their
+    // given variant.
+    fn layer_stats(&self, forced: &[(&'static str, &'static str)]) -> Result<Vec<LayerStats>> {
+        let anchor = self.layer(self.anchor)?;
+        let anchors = match forced.iter().find(|(name, _)| *name == self.anchor) {
+            Some((_, variant)) => vec![self.certain(anchor, variant)?],
+            None => self.distribution(anchor)?.0,
+        };
+
+        let mut layers = vec![];
+        for layer in &self.layers {
+            let forced_variant = forced
+                .iter()
+                .find(|(name, _)| *name == layer.name)
+                .map(|(_, variant)| *variant);
+            let (variants, none) = match (&layer.sampling, forced_variant) {
+                (_, Some(variant)) => (vec![self.certain(layer, variant)?], 0.0),
+                (Sampling::Required | Sampling::Optional, None) => self.distribution(layer)?,
+                (Sampling::If(allows), None) => {
+                    let allowed = anchors
+                        .iter()
+                        .filter(|anchor| allows(&anchor.variant))
+                        .map(|anchor| anchor.probability)
+                        .sum::<f64>();
+                    let (mut variants, none) = self.distribution(layer)?;
+                    for variant in &mut variants {
+                        variant.probability *= allowed;
+                    }
+                    (variants, 1.0 - allowed + allowed * none)
+                }
+                (Sampling::Derived(derive), None) => {
+                    let rarities = layer
+                        .tiers
+                        .iter()
+                        .zip(&self.tier_names)
+                        .flat_map(|(tier, rarity)| {
+                            tier.iter().map(move |variant| (*variant, *rarity))
+                        })
+                        .collect::<BTreeMap<&str, &'static str>>();
+
+                    let mut derived = BTreeMap::<String, f64>::new();
+                    let mut none = 0.0;
+                    for anchor in &anchors {
+                        match derive(&anchor.variant) {
+                            Some(variant) => {
+                                *derived.entry(variant).or_default() += anchor.probability
+                            }
+                            None => none += anchor.probability,
+                        }
+                    }
+                    let variants = derived
+                        .into_iter()
+                        .map(|(variant, probability)| VariantStats {
+                            rarity: rarities.get(variant.as_str()).copied().unwrap_or("Derived"),
+                            variant,
+                            probability,
+                        })
+                        .collect();
+                    (variants, none)
+                }
+            };
+
+            layers.push(LayerStats {
+                layer: layer.name,
+                none,
+                variants,
+            });
+        }
+
+        Ok(layers)
+    }
+
+    // Exact number of distinct metronions and probability of every variant,
+    // without sampling.
+    pub fn stats(&self) -> Result<Stats> {
+        let anchor = self
+            .layers
+            .iter()
+            .find(|layer| layer.name == self.anchor)
+            .ok_or_else(|| Error::Catalog(format!("missing anchor layer {}", self.anchor)))?;
+        let (anchors, _) = self.distribution(anchor)?;
+
+        let mut combinations = 0u128;
+        for anchor_variant in &anchors {
+            let mut count = 1u128;
+            for layer in self.layers.iter().filter(|layer| layer.name != self.anchor) {
+                let variants = layer.tiers.iter().map(Vec::len).sum::<usize>() as u128;
+                let has_none = layer.tiers.iter().any(Vec::is_empty);
+                let options = match &layer.sampling {
+                    Sampling::Required => variants,
+                    Sampling::Optional => variants + u128::from(has_none),
+                    Sampling::If(allows) if allows(&anchor_variant.variant) => {
+                        variants + u128::from(has_none)
+                    }
+                    Sampling::If(_) | Sampling::Derived(_) => 1,
+                };
+                count = count.saturating_mul(options);
+            }
+            combinations = combinations.saturating_add(count);
+        }
+
+        let sets = self.set_stats()?;
+        let no_set = 1.0 - sets.iter().map(|set| set.probability).sum::<f64>();
+
+        // without a set, then mixed with every set forcing its variants
+        let mut layers = self.layer_stats(&[])?;
+        for layer in &mut layers {
+            scale(layer, no_set);
+        }
+        for (set, set_stats) in self.sets.iter().zip(&sets) {
+            for (layer, mut forced) in layers.iter_mut().zip(self.layer_stats(&set.layers)?) {
+                scale(&mut forced, set_stats.probability);
+                layer.none += forced.none;
+                for variant in forced.variants {
+                    match layer
+                        .variants
+                        .iter_mut()
+                        .find(|other| other.variant == variant.variant)
+                    {
+                        Some(other) => other.probability += variant.probability,
+                        None => layer.variants.push(variant),
+                    }
+                }
+            }
+        }
+
+        Ok(Stats {
+            combinations,
+            sets,
+            layers,
+        })
+    }
+}
+
+fn scale(layer: &mut LayerStats, probability: f64) {
+    layer.none *= probability;
+    for variant in &mut layer.variants {
+        variant.probability *= probability;
+    }
+}
+
+// The model the tests of the stats and the solver build on: two tiers, a
+// required Body anchoring the others with Body_1 Common and Body_2 Rare, a
+// required Hand with two Common variants, then `layers`.
+#[cfg(test)]
+pub fn test_model(layers: Vec<Layer>, sets: Vec<Set>) -> Model {
+    let mut model = Model {
+        tier_names: vec!["Common", "Rare"],
+        // weights need not sum to one
+        tier_probabilities: vec![3.0, 1.0],
+        anchor: "Body",
+        layers: vec![
+            Layer {
+                name: "Body",
+                tiers: vec![vec!["Body_1"], vec!["Body_2"]],
+                sampling: Sampling::Required,
+            },
+            Layer {
+                name: "Hand",
+                tiers: vec![vec!["Hand_1", "Hand_2"], vec![]],
+                sampling: Sampling::Required,
+            },
+        ],
+        sets,
+    };
+    model.layers.extend(layers);
+    model
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn model(sets: Vec<Set>) -> Model {
+        test_model(
+            vec![
+                Layer {
+                    name: "Acc",
+                    tiers: vec![vec![], vec!["Acc_1"]],
+                    sampling: Sampling::If(Box::new(|body| body == "Body_1")),
+                },
+                Layer {
+                    name: "Hair",
+                    tiers: vec![vec!["Hair_1"], vec!["Hair_2"]],
+                    sampling: Sampling::Derived(Box::new(|body| {
+                        Some(body.replace("Body", "Hair"))
+                    })),
+                },
+            ],
+            sets,
+        )
+    }
+
+    // (variant, probability) of every variant of the layer, then the
+    // probability of none
+    fn probabilities(stats: &Stats, layer: &str) -> (Vec<(String, f64)>, f64) {
+        let layer = stats
+            .layers
+            .iter()
+            .find(|stats| stats.layer == layer)
+            .expect("Missing layer");
+        let mut variants = layer
+            .variants
+            .iter()
+            .map(|variant| (variant.variant.clone(), variant.probability))
+            .collect::<Vec<(String, f64)>>();
+        variants.sort_by(|a, b| a.0.cmp(&b.0));
+        (variants, layer.none)
+    }
+
+    fn assert_probabilities(stats: &Stats, layer: &str, expected: &[(&str, f64)], none: f64) {
+        let (variants, actual_none) = probabilities(stats, layer);
+        assert_eq!(variants.len(), expected.len(), "{layer}: {variants:?}");
+        for ((variant, probability), (expected_variant, expected_probability)) in
+            variants.iter().zip(expected)
+        {
+            assert_eq!(variant, expected_variant);
+            assert!(
+                (probability - expected_probability).abs() < 1e-12,
+                "{variant}: {probability} != {expected_probability}"
+            );
+        }
+        assert!(
+            (actual_none - none).abs() < 1e-12,
+            "{layer}: none {actual_none}"
+        );
+    }
+
+    #[test]
+    fn two_variant_layer_follows_the_tier_weights() {
+        let stats = model(vec![]).stats().unwrap();
+
+        assert_probabilities(&stats, "Body", &[("Body_1", 0.75), ("Body_2", 0.25)], 0.0);
+    }
+
+    #[test]
+    fn required_layer_rolls_again_over_empty_tiers() {
+        let stats = model(vec![]).stats().unwrap();
+
+        assert_probabilities(&stats, "Hand", &[("Hand_1", 0.5), ("Hand_2", 0.5)], 0.0);
+    }
+
+    #[test]
+    fn conditional_layer_is_sampled_only_when_the_anchor_allows_it() {
+        let stats = model(vec![]).stats().unwrap();
+
+        // Body_1 with 0.75, then the Rare tier with 0.25
+        assert_probabilities(&stats, "Acc", &[("Acc_1", 0.1875)], 0.8125);
+    }
+
+    #[test]
+    fn derived_layer_follows_the_anchor() {
+        let stats = model(vec![]).stats().unwrap();
+
+        assert_probabilities(&stats, "Hair", &[("Hair_1", 0.75), ("Hair_2", 0.25)], 0.0);
+    }
+
+    #[test]
+    fn combinations_count_the_conditional_layer_per_anchor() {
+        // Body_1: 2 hands times Acc_1 or none, Body_2: 2 hands
+        assert_eq!(model(vec![]).stats().unwrap().combinations, 6);
+    }
+
+    #[test]
+    fn sets_mix_their_forced_variants_in() {
+        let stats = model(vec![Set {
+            name: "Gloved",
+            tier: 1,
+            layers: vec![("Hand", "Hand_2")],
+        }])
+        .stats()
+        .unwrap();
+
+        assert_eq!(stats.sets.len(), 1);
+        assert_eq!(stats.sets[0].probability, 0.25);
+        // half of the 0.75 without the set, plus the whole set
+        assert_probabilities(&stats, "Hand", &[("Hand_1", 0.375), ("Hand_2", 0.625)], 0.0);
+        assert_probabilities(&stats, "Body", &[("Body_1", 0.75), ("Body_2", 0.25)], 0.0);
+    }
+
+    #[test]
+    fn check_sets_needs_an_anchor_fitting_the_conditional_layers() {
+        let set = |layers| {
+            model(vec![Set {
+                name: "Masked",
+                tier: 1,
+                layers,
+            }])
+        };
+
+        assert!(set(vec![("Acc", "Acc_1"), ("Body", "Body_1")])
+            .check_sets()
+            .is_ok());
+        // Body_2 does not allow Acc, nor may a rolled Body
+        for layers in [
+            vec![("Acc", "Acc_1"), ("Body", "Body_2")],
+            vec![("Acc", "Acc_1")],
+        ] {
+            let Err(message) = set(layers).check_sets() else {
+                panic!("Set should not fit");
+            };
+            assert!(message.contains("without a Body variant"), "{message}");
+        }
+        assert!(set(vec![("Hair", "Hair_1")]).check_sets().is_err());
+    }
+}
@@ -0,0 +1,276 @@
+use serde::Serialize;
+use std::cell::RefCell;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::error::{Error, IoContext, Result};
+use crate::files;
+use crate::progress::Progress;
+use crate::render;
+use crate::shutdown;
+
+const STICKER_SIZE: u32 = 512;
+const EMOJI_SIZE: u32 = 128;
+
+// WebP quality steps tried in order until a render fits the size budget
+const QUALITY_STEPS: [u32; 7] = [95, 90, 80, 70, 60, 50, 40];
+
+#[derive(Debug, Clone, Copy)]
+enum StickerKind {
+    Sticker,
+    Emoji,
+}
+
+impl StickerKind {
+    fn dir_name(&self) -> &'static str {
+        match self {
+            StickerKind::Sticker => "stickers",
+            StickerKind::Emoji => "emoji",
+        }
+    }
+
+    fn size(&self) -> u32 {
+        match self {
+            StickerKind::Sticker => STICKER_SIZE,
+            StickerKind::Emoji => EMOJI_SIZE,
+        }
+    }
+
+    // keep a transparent margin around the artwork, as messaging apps recommend
+    fn padding(&self) -> u32 {
+        self.size() / 32
+    }
+}
+
+// Size budget of each kind of file, in bytes.
+#[derive(Debug, Clone, Copy)]
+struct Budget {
+    sticker: u64,
+    emoji: u64,
+}
+
+impl Budget {
+    fn max_bytes(&self, kind: StickerKind) -> u64 {
+        match kind {
+            StickerKind::Sticker => self.sticker,
+            StickerKind::Emoji => self.emoji,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Manifest {
+    name: String,
+    sticker_size: u32,
+    emoji_size: u32,
+    max_bytes: u64,
+    emoji_max_bytes: u64,
+    stickers: Vec<ManifestEntry>,
+}
+
+#[derive(Debug, Serialize)]
+struct ManifestEntry {
+    metronion: usize,
+    emotion: String,
+    sticker: Rendered,
+    emoji: Rendered,
+}
+
+#[derive(Debug, Serialize)]
+struct Rendered {
+    file: PathBuf,
+    bytes: u64,
+    quality: u32,
+}
+
+pub async fn export_stickers(
+    stacks: Vec<(usize, Vec<Vec<String>>)>,
+    input_dir: &Path,
+    output_dir: &Path,
+    name: &str,
+    max_bytes: u64,
+    emoji_max_bytes: u64,
+    jobs: usize,
+) -> Result<()> {
+    std::fs::create_dir_all(output_dir).with_path(output_dir)?;
+
+    info!("Number of metronions = {:?}", stacks.len());
+
+    let budget = Budget {
+        sticker: max_bytes,
+        emoji: emoji_max_bytes,
+    };
+
+    let progress = Progress::new("stickers", stacks.len());
+    // filled by the render tasks as their emotions are exported
+    let stickers = Arc::new(Mutex::new(vec![]));
+    let tasks = stacks.into_iter().map(|(index, emotions)| {
+        let parts = emotions.concat();
+        let task = tokio::task::spawn_blocking({
+            let input_dir = input_dir.to_path_buf();
+            let output_dir = output_dir.to_path_buf();
+            let stickers = Arc::clone(&stickers);
+            move || {
+                let mut failures = vec![];
+                for parts in emotions {
+                    let entry = RefCell::new(None);
+                    match render::with_retries(index + 1, &parts, || {
+                        let exported =
+                            export_emotion(index, &parts, &input_dir, &output_dir, budget)
+                                .map_err(|err| err.to_string())?;
+                        *entry.borrow_mut() = Some(exported);
+                        Ok(())
+                    }) {
+                        Ok(()) => stickers
+                            .lock()
+                            .expect("Sticker manifest")
+                            .extend(entry.into_inner()),
+                        Err(failure) => failures.push(failure),
+                    }
+                }
+                failures
+            }
+        });
+        render::join_render(task, index, parts)
+    });
+
+    let report = render::collect(tasks, jobs, &progress).await;
+    let mut stickers = std::mem::take(&mut *stickers.lock().expect("Sticker manifest"));
+    stickers.sort_by_key(|sticker| sticker.metronion);
+
+    let manifest = Manifest {
+        name: name.to_string(),
+        sticker_size: STICKER_SIZE,
+        emoji_size: EMOJI_SIZE,
+        max_bytes,
+        emoji_max_bytes,
+        stickers,
+    };
+
+    let manifest_file = output_dir.join("manifest.json");
+    files::write_json_atomic(&manifest_file, &manifest)?;
+
+    info!(
+        "Write {} stickers to manifest {manifest_file:?}",
+        manifest.stickers.len()
+    );
+
+    if shutdown::is_cancelled() {
+        return Err(Error::Cancelled { checkpoint: None });
+    }
+    render::write_failures(output_dir, &report.failures)
+}
+
+fn export_emotion(
+    index: usize,
+    parts: &[String],
+    input_dir: &Path,
+    output_dir: &Path,
+    budget: Budget,
+) -> Result<ManifestEntry> {
+    let emotion = parts
+        .iter()
+        .find(|item| item.contains("NFT_Emo"))
+        .cloned()
+        .unwrap_or_default();
+
+    let sticker = render_sticker(
+        StickerKind::Sticker,
+        index,
+        &emotion,
+        parts,
+        input_dir,
+        output_dir,
+        budget.max_bytes(StickerKind::Sticker),
+    )?;
+    let emoji = render_sticker(
+        StickerKind::Emoji,
+        index,
+        &emotion,
+        parts,
+        input_dir,
+        output_dir,
+        budget.max_bytes(StickerKind::Emoji),
+    )?;
+
+    Ok(ManifestEntry {
+        metronion: index,
+        emotion,
+        sticker,
+        emoji,
+    })
+}
+
+fn render_sticker(
+    kind: StickerKind,
+    index: usize,
+    emotion: &str,
+    parts: &[String],
+    input_dir: &Path,
+    output_dir: &Path,
+    max_bytes: u64,
+) -> Result<Rendered> {
+    let relative_file = PathBuf::from(kind.dir_name())
+        .join(format!("{index:}"))
+        .join(format!("{index:}_{emotion:}.webp"));
+    let output_file = output_dir.join(&relative_file);
+    let partial_file = files::partial_path(&output_file);
+    let sticker_dir = output_dir.join(kind.dir_name()).join(format!("{index:}"));
+    std::fs::create_dir_all(&sticker_dir).with_path(&sticker_dir)?;
+
+    let inputs = crate::layer_images(input_dir, output_dir, index, parts).map_err(|message| {
+        Error::Render {
+            token_id: index + 1,
+            message,
+        }
+    })?;
+
+    let inner = kind.size() - 2 * kind.padding();
+    let resize = format!("{inner}x{inner}");
+    let extent = format!("{size}x{size}", size = kind.size());
+
+    for quality in QUALITY_STEPS {
+        let output = std::process::Command::new("magick")
+            .args(["convert"])
+            .args(render::compose_args(&inputs))
+            .args(["-trim", "+repage"])
+            .args(["-resize", &resize, "-gravity", "center", "-extent", &extent])
+            .args(["-quality", &quality.to_string()])
+            .arg(&partial_file)
+            .output()
+            .with_path(&output_file)?;
+
+        if !output.status.success() {
+            let _ = std::fs::remove_file(&partial_file);
+            return Err(Error::Render {
+                token_id: index + 1,
+                message: format!(
+                    "Command failed with {}: {}",
+                    output.status,
+                    String::from_utf8_lossy(&output.stderr).trim()
+                ),
+            });
+        }
+
+        let bytes = std::fs::metadata(&partial_file)
+            .with_path(&partial_file)?
+            .len();
+        if bytes <= max_bytes {
+            std::fs::rename(&partial_file, &output_file).with_path(&output_file)?;
+            return Ok(Rendered {
+                file: relative_file,
+                bytes,
+                quality,
+            });
+        }
+    }
+
+    std::fs::remove_file(&partial_file).with_path(&partial_file)?;
+    Err(Error::Render {
+        token_id: index + 1,
+        message: format!(
+            "{} with emotions {emotion:?} exceeds {max_bytes} bytes",
+            kind.dir_name()
+        ),
+    })
+}
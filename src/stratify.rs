@@ -0,0 +1,161 @@
+use rand::prelude::*;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+pub const BLOCKS_FILE: &str = "rarity_blocks.json";
+
+// Order `items` to fill the ids in `slots` so that every tier is spread
+// evenly over the blocks of `block_size` ids. The tiers are interleaved in
+// proportion to their size, then the items of each block are shuffled so
+// the position of a tier inside a block stays random.
+pub fn stratify<K: Ord + Copy, T>(
+    slots: &[usize],
+    block_size: usize,
+    items: Vec<(K, T)>,
+) -> Vec<T> {
+    let mut rng = thread_rng();
+
+    let mut pools: BTreeMap<K, Vec<T>> = BTreeMap::new();
+    for (tier, item) in items {
+        pools.entry(tier).or_default().push(item);
+    }
+    for pool in pools.values_mut() {
+        pool.shuffle(&mut rng);
+    }
+
+    // the tier the furthest behind its share goes next
+    let totals = pools
+        .iter()
+        .map(|(tier, pool)| (*tier, pool.len()))
+        .collect::<BTreeMap<K, usize>>();
+    let mut placed = BTreeMap::<K, usize>::new();
+    let mut labels = Vec::with_capacity(slots.len());
+    for _ in 0..slots.len() {
+        let tier = totals
+            .iter()
+            .filter(|(tier, total)| placed.get(*tier).copied().unwrap_or(0) < **total)
+            .min_by(|(a, a_total), (b, b_total)| {
+                let share = |tier: &K, total: usize| {
+                    (placed.get(tier).copied().unwrap_or(0) as f64 + 0.5) / total as f64
+                };
+                share(a, **a_total).total_cmp(&share(b, **b_total))
+            })
+            .map(|(tier, _)| *tier)
+            .expect("More slots than items");
+        *placed.entry(tier).or_default() += 1;
+        labels.push(tier);
+    }
+
+    let mut ordered = Vec::with_capacity(slots.len());
+    let mut labels = labels.into_iter();
+    for block in slots.chunk_by(|a, b| (a - 1) / block_size == (b - 1) / block_size) {
+        let mut items = labels
+            .by_ref()
+            .take(block.len())
+            .map(|tier| {
+                pools
+                    .get_mut(&tier)
+                    .and_then(Vec::pop)
+                    .expect("Missing item")
+            })
+            .collect::<Vec<T>>();
+        items.shuffle(&mut rng);
+        ordered.extend(items);
+    }
+
+    ordered
+}
+
+#[derive(Debug, Serialize)]
+pub struct BlockCounts<K: Ord> {
+    pub first_id: usize,
+    pub last_id: usize,
+    pub counts: BTreeMap<K, usize>,
+}
+
+// Number of metronions of each tier in every block of `block_size` ids.
+pub fn block_counts<K: Ord>(
+    block_size: usize,
+    tiers: impl IntoIterator<Item = (usize, K)>,
+) -> Vec<BlockCounts<K>> {
+    let mut blocks: BTreeMap<usize, BTreeMap<K, usize>> = BTreeMap::new();
+    for (id, tier) in tiers {
+        *blocks
+            .entry((id - 1) / block_size)
+            .or_default()
+            .entry(tier)
+            .or_default() += 1;
+    }
+
+    blocks
+        .into_iter()
+        .map(|(block, counts)| BlockCounts {
+            first_id: block * block_size + 1,
+            last_id: (block + 1) * block_size,
+            counts,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stratify_spreads_each_tier_evenly_over_the_blocks() {
+        let slots = (1..=20).collect::<Vec<usize>>();
+        let items = (0..20)
+            .map(|item| (usize::from(item < 4), item))
+            .collect::<Vec<(usize, usize)>>();
+
+        let ordered = stratify(&slots, 5, items);
+
+        // 4 rare items over 4 blocks of 5 ids, one in each
+        for block in ordered.chunks(5) {
+            assert_eq!(block.iter().filter(|item| **item < 4).count(), 1);
+        }
+        let mut sorted = ordered.clone();
+        sorted.sort_unstable();
+        assert_eq!(sorted, (0..20).collect::<Vec<usize>>());
+    }
+
+    #[test]
+    fn stratify_fills_the_blocks_around_taken_ids() {
+        // ids 3 and 8 are taken, so the blocks of 3 hold 2, 3 and 2 slots
+        let slots = [1, 2, 4, 5, 6, 7, 9];
+        let items = (0..7)
+            .map(|item| (usize::from(item < 3), item))
+            .collect::<Vec<(usize, usize)>>();
+
+        let ordered = stratify(&slots, 3, items);
+
+        let rare = |items: &[usize]| items.iter().filter(|item| **item < 3).count();
+        assert_eq!(ordered.len(), 7);
+        assert_eq!(rare(&ordered[..2]), 1);
+        assert_eq!(rare(&ordered[2..5]), 1);
+        assert_eq!(rare(&ordered[5..]), 1);
+    }
+
+    #[test]
+    fn block_counts_count_each_tier_per_block() {
+        let tiers = [
+            (1, "Common"),
+            (2, "Rare"),
+            (3, "Common"),
+            (4, "Common"),
+            (7, "Rare"),
+        ];
+
+        let blocks = block_counts(3, tiers);
+
+        assert_eq!(blocks.len(), 3);
+        assert_eq!((blocks[0].first_id, blocks[0].last_id), (1, 3));
+        assert_eq!(
+            blocks[0].counts,
+            BTreeMap::from([("Common", 2), ("Rare", 1)])
+        );
+        assert_eq!(blocks[1].counts, BTreeMap::from([("Common", 1)]));
+        assert_eq!((blocks[2].first_id, blocks[2].last_id), (7, 9));
+        assert_eq!(blocks[2].counts, BTreeMap::from([("Rare", 1)]));
+    }
+}